@@ -0,0 +1,30 @@
+#![no_main]
+
+// The parsing modules are included directly since plume is only built as a binary.
+#[allow(dead_code)]
+#[path = "../../src/elvl.rs"]
+mod elvl;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Put the input behind a bitmap header that points at it, so the fuzzer spends its time inside the metadata.
+    let mut file = vec![b'B', b'M', 0, 0, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0];
+    file.extend_from_slice(data);
+
+    elvl::elvl_read(data);
+
+    let (chunks, _) = elvl::elvl_read(&file);
+
+    // Anything that was read must survive being written and read again.
+    let written = elvl::elvl_write(&chunks);
+
+    let mut rewritten_file = file[..14].to_vec();
+    rewritten_file.extend_from_slice(&written);
+
+    // Chunks that failed to parse are written back unparsed, so they give the same warnings again.
+    let (rewritten_chunks, _) = elvl::elvl_read(&rewritten_file);
+
+    assert_eq!(chunks.len(), rewritten_chunks.len());
+    assert_eq!(written, elvl::elvl_write(&rewritten_chunks));
+});
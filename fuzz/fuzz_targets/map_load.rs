@@ -0,0 +1,25 @@
+#![no_main]
+
+// The parsing modules are included directly since plume is only built as a binary.
+#[allow(dead_code)]
+#[path = "../../src/elvl.rs"]
+mod elvl;
+#[allow(dead_code)]
+#[path = "../../src/map.rs"]
+mod map;
+#[allow(dead_code)]
+#[path = "../../src/collision.rs"]
+mod collision;
+#[allow(dead_code)]
+#[path = "../../src/tileset.rs"]
+mod tileset;
+#[allow(dead_code)]
+#[path = "../../src/walkability.rs"]
+mod walkability;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Errors are fine, only panics are failures.
+    let _ = map::Map::from_bytes(data);
+});
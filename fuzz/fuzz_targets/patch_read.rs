@@ -0,0 +1,38 @@
+#![no_main]
+
+// The parsing modules are included directly since plume is only built as a binary.
+#[allow(dead_code)]
+#[path = "../../src/collision.rs"]
+mod collision;
+#[allow(dead_code)]
+#[path = "../../src/diff.rs"]
+mod diff;
+#[allow(dead_code)]
+#[path = "../../src/elvl.rs"]
+mod elvl;
+#[allow(dead_code)]
+#[path = "../../src/map.rs"]
+mod map;
+#[allow(dead_code)]
+#[path = "../../src/tileset.rs"]
+mod tileset;
+#[allow(dead_code)]
+#[path = "../../src/walkability.rs"]
+mod walkability;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Patches that read back must write back the same way and apply without panicking.
+    if let Ok(patch) = diff::MapDiff::from_bytes(data) {
+        assert_eq!(
+            diff::MapDiff::from_bytes(&patch.to_bytes())
+                .unwrap()
+                .to_bytes(),
+            patch.to_bytes()
+        );
+
+        let mut map = map::Map::empty();
+        let _ = patch.apply(&mut map);
+    }
+});
@@ -0,0 +1,79 @@
+use crate::map::{
+    TILE_ID_FIRST_DOOR, TILE_ID_FLAG, TILE_ID_GOAL, TILE_ID_INVISIBLE_WALL, TILE_ID_LARGE_ASTEROID,
+    TILE_ID_LAST_DOOR, TILE_ID_SAFE, TILE_ID_SMALL_ASTEROID_1, TILE_ID_SMALL_ASTEROID_2,
+    TILE_ID_SPACE_STATION, TILE_ID_WORMHOLE, TileId,
+};
+
+pub const TILE_ID_FIRST_FLY_OVER: TileId = 173;
+pub const TILE_ID_LAST_FLY_OVER: TileId = 175;
+pub const TILE_ID_FIRST_FLY_UNDER: TileId = 176;
+pub const TILE_ID_LAST_FLY_UNDER: TileId = 190;
+
+// What a tile id does in Continuum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TileKind {
+    Empty,
+    Wall,
+    // Opens and closes over time, so it's only sometimes solid.
+    Door,
+    Flag,
+    Safe,
+    Goal,
+    // Drawn above ships, which can fly through it.
+    FlyOver,
+    // Drawn below ships, which can fly through it.
+    FlyUnder,
+    InvisibleWall,
+    Asteroid,
+    SpaceStation,
+    Wormhole,
+    // Ids the client doesn't do anything with.
+    Unused,
+}
+
+impl TileKind {
+    pub fn from_id(id: TileId) -> Self {
+        match id {
+            0 => TileKind::Empty,
+            1..TILE_ID_FIRST_DOOR => TileKind::Wall,
+            TILE_ID_FIRST_DOOR..=TILE_ID_LAST_DOOR => TileKind::Door,
+            TILE_ID_FLAG => TileKind::Flag,
+            TILE_ID_SAFE => TileKind::Safe,
+            TILE_ID_GOAL => TileKind::Goal,
+            TILE_ID_FIRST_FLY_OVER..=TILE_ID_LAST_FLY_OVER => TileKind::FlyOver,
+            TILE_ID_FIRST_FLY_UNDER..=TILE_ID_LAST_FLY_UNDER => TileKind::FlyUnder,
+            TILE_ID_INVISIBLE_WALL => TileKind::InvisibleWall,
+            TILE_ID_LARGE_ASTEROID | TILE_ID_SMALL_ASTEROID_1 | TILE_ID_SMALL_ASTEROID_2 => {
+                TileKind::Asteroid
+            }
+            TILE_ID_SPACE_STATION => TileKind::SpaceStation,
+            TILE_ID_WORMHOLE => TileKind::Wormhole,
+            _ => TileKind::Unused,
+        }
+    }
+
+    // Returns true if ships and weapons collide with the tile. Doors are treated as closed.
+    pub fn is_solid(self) -> bool {
+        matches!(
+            self,
+            TileKind::Wall
+                | TileKind::Door
+                | TileKind::InvisibleWall
+                | TileKind::Asteroid
+                | TileKind::SpaceStation
+        )
+    }
+
+    // Returns true if the tile is drawn with its image from the tileset. Doors, flags and goals are animated by the
+    // client and objects have their own images, so those aren't.
+    pub fn uses_tileset(self) -> bool {
+        matches!(
+            self,
+            TileKind::Wall | TileKind::Safe | TileKind::FlyOver | TileKind::FlyUnder
+        )
+    }
+}
+
+pub fn is_solid(id: TileId) -> bool {
+    TileKind::from_id(id).is_solid()
+}
@@ -0,0 +1,1172 @@
+use crate::{
+    elvl::{self, TileRect},
+    map::{Map, TileId},
+    tileset::{TILESET_HEIGHT, TILESET_WIDTH},
+};
+use anyhow::*;
+use image::{Rgba, RgbaImage};
+use std::collections::{HashMap, HashSet};
+
+const PATCH_MAGIC: &[u8; 4] = b"PLMP";
+const PATCH_VERSION: u32 = 1;
+
+const RECORD_TILES: &[u8; 4] = b"TILE";
+const RECORD_REGION_ADDED: &[u8; 4] = b"RADD";
+const RECORD_REGION_REMOVED: &[u8; 4] = b"RDEL";
+const RECORD_REGION_MODIFIED: &[u8; 4] = b"RMOD";
+const RECORD_ATTRIBUTE_ADDED: &[u8; 4] = b"AADD";
+const RECORD_ATTRIBUTE_REMOVED: &[u8; 4] = b"ADEL";
+const RECORD_ATTRIBUTE_MODIFIED: &[u8; 4] = b"AMOD";
+const RECORD_TILESET_PIXEL: &[u8; 4] = b"TPIX";
+const RECORD_TILESET_ADDED: &[u8; 4] = b"TADD";
+const RECORD_TILESET_REMOVED: &[u8; 4] = b"TDEL";
+
+#[derive(Clone)]
+pub enum Change<T> {
+    Added(T),
+    Removed(T),
+    Modified { old: T, new: T },
+}
+
+// Regions are matched by name. Regions that share a name are matched in the order they come in, so the occurrence
+// tells which of them changed.
+#[derive(Clone)]
+pub struct RegionChange {
+    // The number of earlier regions with the same name.
+    pub occurrence: u32,
+    pub change: Change<elvl::Region>,
+}
+
+// A rectangle of tiles that all changed. The ids are stored in row order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileChange {
+    pub rect: TileRect,
+    pub old: Vec<TileId>,
+    pub new: Vec<TileId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelChange {
+    pub x: u16,
+    pub y: u16,
+    pub old: Rgba<u8>,
+    pub new: Rgba<u8>,
+}
+
+// Maps without a tileset show the placeholder, but that isn't part of the map, so a tileset that was added or removed
+// is a change of its own instead of a change to every pixel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TilesetChange {
+    Added(RgbaImage),
+    Removed(RgbaImage),
+    Pixels(Vec<PixelChange>),
+}
+
+// A change that can't be applied because the map doesn't have the old value. Maps that already have the new value
+// don't conflict, so a diff can be applied more than once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    Tile {
+        x: u16,
+        y: u16,
+        expected: TileId,
+        found: TileId,
+    },
+    // The region with the name is different, missing or already exists.
+    Region(Vec<u8>),
+    // The attribute with the key has a different value, is missing or already exists.
+    Attribute(Vec<u8>),
+    // The pixel is different or outside of the tileset.
+    TilesetPixel {
+        x: u16,
+        y: u16,
+    },
+    // The tileset is different, missing or already exists.
+    Tileset,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Conflict::Tile {
+                x,
+                y,
+                expected,
+                found,
+            } => write!(
+                f,
+                "tile at ({}, {}) is {} instead of {}",
+                x, y, found, expected
+            ),
+            Conflict::Region(name) => {
+                write!(f, "region {} was changed", elvl::decode_text(name))
+            }
+            Conflict::Attribute(key) => {
+                write!(f, "attribute {} was changed", elvl::decode_text(key))
+            }
+            Conflict::TilesetPixel { x, y } => {
+                write!(f, "tileset pixel ({}, {}) was changed", x, y)
+            }
+            Conflict::Tileset => write!(f, "tileset was changed"),
+        }
+    }
+}
+
+// Everything that changed between two maps. Regions are matched by name and occurrence, and attributes by key.
+#[derive(Clone, Default)]
+pub struct MapDiff {
+    pub tiles: Vec<TileChange>,
+    pub regions: Vec<RegionChange>,
+    pub attributes: Vec<Change<elvl::Attribute>>,
+    pub tileset: Option<TilesetChange>,
+}
+
+impl MapDiff {
+    pub fn new(old: &Map, new: &Map) -> Self {
+        Self {
+            tiles: diff_tiles(old, new),
+            regions: diff_regions(old, new),
+            attributes: diff_attributes(old, new),
+            tileset: diff_tileset(old, new),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+            && self.regions.is_empty()
+            && self.attributes.is_empty()
+            && self.tileset.is_none()
+    }
+
+    pub fn changed_tile_count(&self) -> usize {
+        self.tiles.iter().map(|change| change.new.len()).sum()
+    }
+
+    // Draws the old map with the changes on top. Added tiles are green, removed tiles are red and tiles that were
+    // replaced with a different tile are yellow.
+    pub fn to_image(&self, old: &Map) -> RgbaImage {
+        let mut image = RgbaImage::from_fn(1024, 1024, |x, y| {
+            if old.tiles[y as usize * 1024 + x as usize] != 0 {
+                Rgba([80, 80, 80, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+
+        for change in &self.tiles {
+            let rect = change.rect;
+            let mut index = 0;
+
+            for y in rect.top..rect.bottom {
+                for x in rect.left..rect.right {
+                    let color = match (change.old[index], change.new[index]) {
+                        (0, _) => Rgba([0, 255, 0, 255]),
+                        (_, 0) => Rgba([255, 0, 0, 255]),
+                        _ => Rgba([255, 255, 0, 255]),
+                    };
+
+                    image.put_pixel(x as u32, y as u32, color);
+                    index += 1;
+                }
+            }
+        }
+
+        image
+    }
+
+    // Finds the changes that don't match the map. Tiles and pixels are matched by position, regions by name and
+    // occurrence, and attributes by key.
+    pub fn conflicts(&self, map: &Map) -> Vec<Conflict> {
+        let mut conflicts = vec![];
+
+        for change in &self.tiles {
+            let rect = change.rect;
+            let mut index = 0;
+
+            for y in rect.top..rect.bottom {
+                for x in rect.left..rect.right {
+                    let found = map.tiles[y as usize * 1024 + x as usize];
+                    let expected = change.old[index];
+
+                    if found != expected && found != change.new[index] {
+                        conflicts.push(Conflict::Tile {
+                            x,
+                            y,
+                            expected,
+                            found,
+                        });
+                    }
+
+                    index += 1;
+                }
+            }
+        }
+
+        for RegionChange { occurrence, change } in &self.regions {
+            let (name, old, new) = match change {
+                Change::Added(new) => (&new.name, None, Some(new)),
+                Change::Removed(old) => (&old.name, Some(old), None),
+                Change::Modified { old, new } => (&old.name, Some(old), Some(new)),
+            };
+
+            let found = map
+                .regions()
+                .filter(|region| region.name == *name)
+                .nth(*occurrence as usize);
+
+            if found != old && found != new {
+                conflicts.push(Conflict::Region(name.clone()));
+            }
+        }
+
+        for change in &self.attributes {
+            let (key, old, new) = match change {
+                Change::Added(new) => (&new.key, None, Some(&new.value)),
+                Change::Removed(old) => (&old.key, Some(&old.value), None),
+                Change::Modified { old, new } => (&old.key, Some(&old.value), Some(&new.value)),
+            };
+
+            // The first attribute with the key is the one that gets changed.
+            let found = map
+                .get_attributes()
+                .into_iter()
+                .find(|attribute| attribute.key == *key)
+                .map(|attribute| &attribute.value);
+
+            if found != old && found != new {
+                conflicts.push(Conflict::Attribute(key.clone()));
+            }
+        }
+
+        match (&self.tileset, &map.tileset) {
+            (Some(TilesetChange::Added(new)), Some(found)) if found != new => {
+                conflicts.push(Conflict::Tileset)
+            }
+            (Some(TilesetChange::Removed(old)), Some(found)) if found != old => {
+                conflicts.push(Conflict::Tileset)
+            }
+            (Some(TilesetChange::Pixels(_)), None) => conflicts.push(Conflict::Tileset),
+            (Some(TilesetChange::Pixels(pixels)), Some(tileset)) => {
+                for pixel in pixels {
+                    let found = tileset.get_pixel_checked(pixel.x as u32, pixel.y as u32);
+
+                    if found != Some(&pixel.old) && found != Some(&pixel.new) {
+                        conflicts.push(Conflict::TilesetPixel {
+                            x: pixel.x,
+                            y: pixel.y,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        conflicts
+    }
+
+    // Makes the same changes to the map. The map doesn't have to be the one the diff was made from, but it needs to
+    // have the old values wherever there are changes. Nothing is changed if any of the changes conflict.
+    pub fn apply(&self, map: &mut Map) -> anyhow::Result<()> {
+        // Showing every conflict could be a lot, so only the first ones are listed.
+        const SHOWN_CONFLICTS: usize = 10;
+
+        let conflicts = self.conflicts(map);
+
+        if !conflicts.is_empty() {
+            let shown: Vec<String> = conflicts
+                .iter()
+                .take(SHOWN_CONFLICTS)
+                .map(|conflict| conflict.to_string())
+                .collect();
+
+            bail!(
+                "{} changes conflict with the map: {}",
+                conflicts.len(),
+                shown.join(", ")
+            );
+        }
+
+        for change in &self.tiles {
+            let rect = change.rect;
+            let mut new_ids = change.new.iter();
+
+            for y in rect.top..rect.bottom {
+                for x in rect.left..rect.right {
+                    map.tiles[y as usize * 1024 + x as usize] = *new_ids.next().unwrap();
+                }
+            }
+        }
+
+        // Removing a region changes the occurrence of the later regions with the same name, so regions are removed
+        // from the last occurrence back before any are added. Regions that were already removed or added are skipped.
+        let mut removed = vec![];
+        let mut added = vec![];
+
+        for RegionChange { occurrence, change } in &self.regions {
+            match change {
+                Change::Modified { new, .. } => {
+                    if let Some(index) = region_index(map, &new.name, *occurrence) {
+                        map.elvl[index] = elvl::Chunk::Region(new.clone());
+                    }
+                }
+                Change::Removed(old) => removed.push((*occurrence, old)),
+                Change::Added(new) => added.push((*occurrence, new)),
+            }
+        }
+
+        removed.sort_by_key(|(occurrence, _)| std::cmp::Reverse(*occurrence));
+        added.sort_by_key(|(occurrence, _)| *occurrence);
+
+        for (occurrence, old) in removed {
+            if let Some(index) = region_index(map, &old.name, occurrence) {
+                map.elvl.remove(index);
+            }
+        }
+
+        for (occurrence, new) in added {
+            if region_index(map, &new.name, occurrence).is_none() {
+                map.elvl.push(elvl::Chunk::Region(new.clone()));
+            }
+        }
+
+        for change in &self.attributes {
+            match change {
+                Change::Added(attribute) | Change::Modified { new: attribute, .. } => {
+                    map.attributes_mut().set(&attribute.key, &attribute.value);
+                }
+                Change::Removed(attribute) => map.attributes_mut().remove(&attribute.key),
+            }
+        }
+
+        match &self.tileset {
+            Some(TilesetChange::Added(tileset)) => map.set_tileset(tileset.clone())?,
+            Some(TilesetChange::Removed(_)) => map.use_default_tileset(),
+            Some(TilesetChange::Pixels(pixels)) => {
+                // There are no conflicts, so the map has a tileset.
+                let mut tileset = map.tileset.clone().unwrap();
+
+                for pixel in pixels {
+                    tileset.put_pixel(pixel.x as u32, pixel.y as u32, pixel.new);
+                }
+
+                map.set_tileset(tileset)?;
+            }
+            None => {}
+        }
+
+        // The index is rebuilt after everything else since any change to the chunks could affect it.
+        if !self.regions.is_empty() {
+            map.rebuild_region_index();
+        }
+
+        Ok(())
+    }
+
+    // Writes the diff as a patch file. Old values are kept too, so the patch can be reviewed on its own.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+
+        out.extend_from_slice(PATCH_MAGIC);
+        out.extend_from_slice(&PATCH_VERSION.to_le_bytes());
+
+        for change in &self.tiles {
+            let mut payload = vec![];
+
+            for value in [
+                change.rect.left,
+                change.rect.top,
+                change.rect.right,
+                change.rect.bottom,
+            ] {
+                payload.extend_from_slice(&value.to_le_bytes());
+            }
+
+            payload.extend_from_slice(&change.old);
+            payload.extend_from_slice(&change.new);
+            write_record(&mut out, RECORD_TILES, &payload);
+        }
+
+        for RegionChange { occurrence, change } in &self.regions {
+            let mut payload = occurrence.to_le_bytes().to_vec();
+
+            let kind = match change {
+                Change::Added(region) => {
+                    payload.extend_from_slice(&elvl::write_region(region));
+                    RECORD_REGION_ADDED
+                }
+                Change::Removed(region) => {
+                    payload.extend_from_slice(&elvl::write_region(region));
+                    RECORD_REGION_REMOVED
+                }
+                Change::Modified { old, new } => {
+                    write_sized(&mut payload, &elvl::write_region(old));
+                    write_sized(&mut payload, &elvl::write_region(new));
+                    RECORD_REGION_MODIFIED
+                }
+            };
+
+            write_record(&mut out, kind, &payload);
+        }
+
+        for change in &self.attributes {
+            let mut payload = vec![];
+
+            let kind = match change {
+                Change::Added(attribute) => {
+                    write_sized(&mut payload, &attribute.key);
+                    write_sized(&mut payload, &attribute.value);
+                    RECORD_ATTRIBUTE_ADDED
+                }
+                Change::Removed(attribute) => {
+                    write_sized(&mut payload, &attribute.key);
+                    write_sized(&mut payload, &attribute.value);
+                    RECORD_ATTRIBUTE_REMOVED
+                }
+                Change::Modified { old, new } => {
+                    write_sized(&mut payload, &old.key);
+                    write_sized(&mut payload, &old.value);
+                    write_sized(&mut payload, &new.value);
+                    RECORD_ATTRIBUTE_MODIFIED
+                }
+            };
+
+            write_record(&mut out, kind, &payload);
+        }
+
+        match &self.tileset {
+            Some(TilesetChange::Added(tileset)) => {
+                write_record(&mut out, RECORD_TILESET_ADDED, &write_image(tileset))
+            }
+            Some(TilesetChange::Removed(tileset)) => {
+                write_record(&mut out, RECORD_TILESET_REMOVED, &write_image(tileset))
+            }
+            Some(TilesetChange::Pixels(pixels)) => {
+                for pixel in pixels {
+                    let mut payload = vec![];
+
+                    payload.extend_from_slice(&pixel.x.to_le_bytes());
+                    payload.extend_from_slice(&pixel.y.to_le_bytes());
+                    payload.extend_from_slice(&pixel.old.0);
+                    payload.extend_from_slice(&pixel.new.0);
+                    write_record(&mut out, RECORD_TILESET_PIXEL, &payload);
+                }
+            }
+            None => {}
+        }
+
+        out
+    }
+
+    // Reads a patch file written by to_bytes.
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = PatchReader { data };
+
+        if reader.take(4)? != PATCH_MAGIC {
+            bail!("not a map patch");
+        }
+
+        let version = reader.u32()?;
+        if version != PATCH_VERSION {
+            bail!("unsupported map patch version {}", version);
+        }
+
+        let mut diff = Self::default();
+
+        while !reader.data.is_empty() {
+            let kind: [u8; 4] = reader.take(4)?.try_into().unwrap();
+            let size = reader.u32()? as usize;
+            let mut payload = PatchReader {
+                data: reader.take(size)?,
+            };
+
+            match &kind {
+                RECORD_TILES => {
+                    let rect = TileRect {
+                        left: payload.u16()?,
+                        top: payload.u16()?,
+                        right: payload.u16()?,
+                        bottom: payload.u16()?,
+                    };
+
+                    if rect.left > rect.right
+                        || rect.top > rect.bottom
+                        || rect.right > 1024
+                        || rect.bottom > 1024
+                    {
+                        bail!("tile change has an invalid rectangle");
+                    }
+
+                    let count = rect.width() as usize * rect.height() as usize;
+
+                    diff.tiles.push(TileChange {
+                        rect,
+                        old: payload.take(count)?.to_owned(),
+                        new: payload.take(count)?.to_owned(),
+                    });
+                }
+                RECORD_REGION_ADDED | RECORD_REGION_REMOVED | RECORD_REGION_MODIFIED => {
+                    let occurrence = payload.u32()?;
+
+                    let change = match &kind {
+                        RECORD_REGION_ADDED => Change::Added(elvl::read_region(payload.data, 0)?),
+                        RECORD_REGION_REMOVED => {
+                            Change::Removed(elvl::read_region(payload.data, 0)?)
+                        }
+                        _ => {
+                            let old = elvl::read_region(payload.sized()?, 0)?;
+                            let new = elvl::read_region(payload.sized()?, 0)?;
+
+                            if old.name != new.name {
+                                bail!("modified region has a different name");
+                            }
+
+                            Change::Modified { old, new }
+                        }
+                    };
+
+                    diff.regions.push(RegionChange { occurrence, change });
+                }
+                RECORD_ATTRIBUTE_ADDED | RECORD_ATTRIBUTE_REMOVED => {
+                    let attribute = elvl::Attribute {
+                        key: payload.sized()?.to_owned(),
+                        value: payload.sized()?.to_owned(),
+                    };
+
+                    diff.attributes.push(if &kind == RECORD_ATTRIBUTE_ADDED {
+                        Change::Added(attribute)
+                    } else {
+                        Change::Removed(attribute)
+                    });
+                }
+                RECORD_ATTRIBUTE_MODIFIED => {
+                    let key = payload.sized()?.to_owned();
+                    let old_value = payload.sized()?.to_owned();
+                    let new_value = payload.sized()?.to_owned();
+
+                    diff.attributes.push(Change::Modified {
+                        old: elvl::Attribute {
+                            key: key.clone(),
+                            value: old_value,
+                        },
+                        new: elvl::Attribute {
+                            key,
+                            value: new_value,
+                        },
+                    });
+                }
+                RECORD_TILESET_PIXEL => {
+                    let pixel = PixelChange {
+                        x: payload.u16()?,
+                        y: payload.u16()?,
+                        old: Rgba(payload.take(4)?.try_into().unwrap()),
+                        new: Rgba(payload.take(4)?.try_into().unwrap()),
+                    };
+
+                    match &mut diff.tileset {
+                        Some(TilesetChange::Pixels(pixels)) => pixels.push(pixel),
+                        None => diff.tileset = Some(TilesetChange::Pixels(vec![pixel])),
+                        _ => bail!("map patch has more than one tileset change"),
+                    }
+                }
+                RECORD_TILESET_ADDED | RECORD_TILESET_REMOVED => {
+                    if diff.tileset.is_some() {
+                        bail!("map patch has more than one tileset change");
+                    }
+
+                    let tileset = payload.image()?;
+
+                    // Checked here so applying the patch can't fail halfway through.
+                    if tileset.dimensions() != (TILESET_WIDTH, TILESET_HEIGHT) {
+                        bail!("map patch has a tileset with the wrong size");
+                    }
+
+                    diff.tileset = Some(if &kind == RECORD_TILESET_ADDED {
+                        TilesetChange::Added(tileset)
+                    } else {
+                        TilesetChange::Removed(tileset)
+                    });
+                }
+                _ => bail!(
+                    "unknown map patch record {}",
+                    String::from_utf8_lossy(&kind)
+                ),
+            }
+        }
+
+        Ok(diff)
+    }
+}
+
+impl std::fmt::Display for MapDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.tiles.is_empty() {
+            writeln!(
+                f,
+                "{} tiles changed in {} rectangles",
+                self.changed_tile_count(),
+                self.tiles.len()
+            )?;
+        }
+
+        for RegionChange { occurrence, change } in &self.regions {
+            let (sign, region) = match change {
+                Change::Added(region) => ('+', region),
+                Change::Removed(region) => ('-', region),
+                Change::Modified { new, .. } => ('~', new),
+            };
+
+            write!(f, "{} region {}", sign, region.name_text())?;
+
+            // Later regions with the same name are numbered from 2, the first one doesn't need a number.
+            if *occurrence > 0 {
+                write!(f, " #{}", occurrence + 1)?;
+            }
+
+            writeln!(f)?;
+        }
+
+        for change in &self.attributes {
+            match change {
+                Change::Added(attribute) => {
+                    writeln!(f, "+ {}={}", attribute.key_text(), attribute.value_text())?
+                }
+                Change::Removed(attribute) => {
+                    writeln!(f, "- {}={}", attribute.key_text(), attribute.value_text())?
+                }
+                Change::Modified { old, new } => writeln!(
+                    f,
+                    "~ {}={} -> {}",
+                    old.key_text(),
+                    old.value_text(),
+                    new.value_text()
+                )?,
+            }
+        }
+
+        match &self.tileset {
+            Some(TilesetChange::Added(_)) => writeln!(f, "+ tileset")?,
+            Some(TilesetChange::Removed(_)) => writeln!(f, "- tileset")?,
+            Some(TilesetChange::Pixels(pixels)) => {
+                writeln!(f, "{} tileset pixels changed", pixels.len())?
+            }
+            None => {}
+        }
+
+        std::fmt::Result::Ok(())
+    }
+}
+
+// Splits the changed tiles into rectangles. Each rectangle is grown right and then down from the first changed tile
+// that isn't covered yet, so every changed tile ends up in exactly one rectangle.
+fn diff_tiles(old: &Map, new: &Map) -> Vec<TileChange> {
+    let changed = |index: usize| old.tiles[index] != new.tiles[index];
+    let mut covered = vec![false; 1024 * 1024];
+    let mut changes = vec![];
+
+    for y in 0..1024usize {
+        for x in 0..1024usize {
+            let index = y * 1024 + x;
+
+            if covered[index] || !changed(index) {
+                continue;
+            }
+
+            let open = |x: usize, y: usize| {
+                let index = y * 1024 + x;
+                !covered[index] && changed(index)
+            };
+
+            let mut right = x + 1;
+            while right < 1024 && open(right, y) {
+                right += 1;
+            }
+
+            let mut bottom = y + 1;
+            while bottom < 1024 && (x..right).all(|x| open(x, bottom)) {
+                bottom += 1;
+            }
+
+            let mut change = TileChange {
+                rect: TileRect {
+                    left: x as u16,
+                    top: y as u16,
+                    right: right as u16,
+                    bottom: bottom as u16,
+                },
+                old: vec![],
+                new: vec![],
+            };
+
+            for rect_y in y..bottom {
+                for rect_x in x..right {
+                    let index = rect_y * 1024 + rect_x;
+
+                    covered[index] = true;
+                    change.old.push(old.tiles[index]);
+                    change.new.push(new.tiles[index]);
+                }
+            }
+
+            changes.push(change);
+        }
+    }
+
+    changes
+}
+
+// Returns the chunk index of the region with the name and occurrence.
+fn region_index(map: &Map, name: &[u8], occurrence: u32) -> Option<usize> {
+    map.elvl
+        .iter()
+        .enumerate()
+        .filter(|(_, chunk)| matches!(chunk, elvl::Chunk::Region(region) if region.name == name))
+        .nth(occurrence as usize)
+        .map(|(index, _)| index)
+}
+
+// Groups the regions by name. Each group keeps the order of its regions.
+fn regions_by_name(map: &Map) -> HashMap<&[u8], Vec<&elvl::Region>> {
+    let mut groups: HashMap<&[u8], Vec<&elvl::Region>> = HashMap::new();
+
+    for region in map.regions() {
+        groups.entry(&region.name).or_default().push(region);
+    }
+
+    groups
+}
+
+fn diff_regions(old: &Map, new: &Map) -> Vec<RegionChange> {
+    let old_groups = regions_by_name(old);
+    let new_groups = regions_by_name(new);
+    let no_regions = vec![];
+    let mut changes = vec![];
+
+    // Names are visited in the order they first come in, so the changes are in the same order as the regions.
+    let mut seen = HashSet::new();
+    let names = old
+        .regions()
+        .chain(new.regions())
+        .map(|region| region.name.as_slice())
+        .filter(|name| seen.insert(*name));
+
+    for name in names {
+        let old_group = old_groups.get(name).unwrap_or(&no_regions);
+        let new_group = new_groups.get(name).unwrap_or(&no_regions);
+
+        for occurrence in 0..old_group.len().max(new_group.len()) {
+            let change = match (old_group.get(occurrence), new_group.get(occurrence)) {
+                (Some(old_region), Some(new_region)) if old_region != new_region => {
+                    Change::Modified {
+                        old: (*old_region).clone(),
+                        new: (*new_region).clone(),
+                    }
+                }
+                (Some(old_region), None) => Change::Removed((*old_region).clone()),
+                (None, Some(new_region)) => Change::Added((*new_region).clone()),
+                _ => continue,
+            };
+
+            changes.push(RegionChange {
+                occurrence: occurrence as u32,
+                change,
+            });
+        }
+    }
+
+    changes
+}
+
+// Only the first attribute with a key is compared, the same as attribute lookups.
+fn attributes_by_key(map: &Map) -> Vec<&elvl::Attribute> {
+    let mut seen = HashSet::new();
+
+    map.get_attributes()
+        .into_iter()
+        .filter(|attribute| seen.insert(&attribute.key))
+        .collect()
+}
+
+fn diff_attributes(old: &Map, new: &Map) -> Vec<Change<elvl::Attribute>> {
+    let old_attributes = attributes_by_key(old);
+    let new_attributes = attributes_by_key(new);
+    let mut changes = vec![];
+
+    for old_attribute in &old_attributes {
+        match new_attributes
+            .iter()
+            .find(|attribute| attribute.key == old_attribute.key)
+        {
+            Some(new_attribute) => {
+                if new_attribute.value != old_attribute.value {
+                    changes.push(Change::Modified {
+                        old: (*old_attribute).clone(),
+                        new: (*new_attribute).clone(),
+                    });
+                }
+            }
+            None => changes.push(Change::Removed((*old_attribute).clone())),
+        }
+    }
+
+    for new_attribute in &new_attributes {
+        if !old_attributes
+            .iter()
+            .any(|attribute| attribute.key == new_attribute.key)
+        {
+            changes.push(Change::Added((*new_attribute).clone()));
+        }
+    }
+
+    changes
+}
+
+fn diff_tileset(old: &Map, new: &Map) -> Option<TilesetChange> {
+    let (old_tileset, new_tileset) = match (&old.tileset, &new.tileset) {
+        (Some(old_tileset), Some(new_tileset)) => (old_tileset, new_tileset),
+        (None, Some(new_tileset)) => return Some(TilesetChange::Added(new_tileset.clone())),
+        (Some(old_tileset), None) => return Some(TilesetChange::Removed(old_tileset.clone())),
+        (None, None) => return None,
+    };
+
+    // Tilesets are checked to be the same size when they are loaded or set, so this doesn't happen.
+    if old_tileset.dimensions() != new_tileset.dimensions() {
+        return None;
+    }
+
+    let pixels: Vec<PixelChange> = old_tileset
+        .enumerate_pixels()
+        .zip(new_tileset.pixels())
+        .filter(|((_, _, old_pixel), new_pixel)| old_pixel != new_pixel)
+        .map(|((x, y, old_pixel), new_pixel)| PixelChange {
+            x: x as u16,
+            y: y as u16,
+            old: *old_pixel,
+            new: *new_pixel,
+        })
+        .collect();
+
+    if pixels.is_empty() {
+        None
+    } else {
+        Some(TilesetChange::Pixels(pixels))
+    }
+}
+
+fn write_record(out: &mut Vec<u8>, kind: &[u8; 4], payload: &[u8]) {
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+fn write_sized(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+// Images are stored as their size followed by the RGBA pixels, so they read back exactly as they were.
+fn write_image(image: &RgbaImage) -> Vec<u8> {
+    let mut out = vec![];
+
+    out.extend_from_slice(&image.width().to_le_bytes());
+    out.extend_from_slice(&image.height().to_le_bytes());
+    out.extend_from_slice(image.as_raw());
+
+    out
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> PatchReader<'a> {
+    fn take(&mut self, count: usize) -> anyhow::Result<&'a [u8]> {
+        if count > self.data.len() {
+            bail!("map patch is truncated");
+        }
+
+        let (taken, rest) = self.data.split_at(count);
+        self.data = rest;
+
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn sized(&mut self) -> anyhow::Result<&'a [u8]> {
+        let size = self.u32()? as usize;
+        self.take(size)
+    }
+
+    fn image(&mut self) -> anyhow::Result<RgbaImage> {
+        let width = self.u32()?;
+        let height = self.u32()?;
+        let size = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|pixels| pixels.checked_mul(4))
+            .ok_or_else(|| anyhow!("map patch has an invalid image"))?;
+
+        RgbaImage::from_raw(width, height, self.take(size)?.to_owned())
+            .ok_or_else(|| anyhow!("map patch has an invalid image"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{Rng, add_random_spans, random_map},
+        tileset,
+    };
+
+    fn region(name: &str, x: u16, y: u16) -> elvl::Region {
+        let mut region = elvl::Region::empty();
+        region.name = name.as_bytes().to_vec();
+        region.set_tile(x, y);
+
+        region
+    }
+
+    fn map_with_regions(regions: &[elvl::Region]) -> Map {
+        let mut map = Map::empty();
+        map.elvl = regions
+            .iter()
+            .map(|region| elvl::Chunk::Region(region.clone()))
+            .collect();
+        map.rebuild_region_index();
+
+        map
+    }
+
+    // Any tileset of the right size works, and the placeholder is the easiest one to get.
+    fn test_tileset() -> RgbaImage {
+        tileset::placeholder_tileset().clone()
+    }
+
+    // Writes the diff between the maps as a patch, reads it back and applies it to a copy of the old map.
+    fn apply_patch(old: &Map, new: &Map) -> Map {
+        let patch = MapDiff::new(old, new).to_bytes();
+        let diff = MapDiff::from_bytes(&patch).unwrap();
+        assert_eq!(diff.to_bytes(), patch);
+
+        let mut map = Map::from_bytes(&old.to_bytes(true)).unwrap();
+        diff.apply(&mut map).unwrap();
+
+        assert!(MapDiff::new(&map, new).is_empty());
+        map
+    }
+
+    #[test]
+    fn removed_tileset_is_removed() {
+        let mut old = Map::empty();
+        old.set_tileset(test_tileset()).unwrap();
+
+        let new = Map::empty();
+        let map = apply_patch(&old, &new);
+
+        assert!(map.tileset.is_none());
+        assert_eq!(map.to_bytes(true), new.to_bytes(true));
+    }
+
+    #[test]
+    fn added_tileset_is_added() {
+        let old = Map::empty();
+        let mut new = Map::empty();
+        new.set_tileset(test_tileset()).unwrap();
+
+        let map = apply_patch(&old, &new);
+
+        assert_eq!(map.tileset, new.tileset);
+        assert_eq!(map.to_bytes(true), new.to_bytes(true));
+    }
+
+    #[test]
+    fn changed_tileset_pixels_are_changed() {
+        let mut old = Map::empty();
+        old.set_tileset(test_tileset()).unwrap();
+
+        let mut tileset = test_tileset();
+        tileset.put_pixel(3, 4, Rgba([1, 2, 3, 255]));
+
+        let mut new = Map::empty();
+        new.set_tileset(tileset).unwrap();
+
+        let diff = MapDiff::new(&old, &new);
+        assert!(matches!(&diff.tileset, Some(TilesetChange::Pixels(pixels)) if pixels.len() == 1));
+
+        // Pixels can't be changed in a map without a tileset.
+        let mut without_tileset = Map::empty();
+        assert_eq!(diff.conflicts(&without_tileset), [Conflict::Tileset]);
+        assert!(diff.apply(&mut without_tileset).is_err());
+        assert!(without_tileset.tileset.is_none());
+
+        assert_eq!(apply_patch(&old, &new).tileset, new.tileset);
+    }
+
+    #[test]
+    fn maps_without_tilesets_have_no_tileset_change() {
+        assert!(MapDiff::new(&Map::empty(), &Map::empty()).is_empty());
+    }
+
+    #[test]
+    fn regions_with_the_same_name_are_matched_in_order() {
+        let old = map_with_regions(&[region("a", 1, 0), region("a", 2, 0)]);
+        let new = map_with_regions(&[region("b", 1, 0), region("a", 2, 0)]);
+
+        let map = apply_patch(&old, &new);
+        let mut regions: Vec<_> = map.regions().cloned().collect();
+        let mut expected: Vec<_> = new.regions().cloned().collect();
+
+        // Added regions go after the others, so only the regions themselves are compared.
+        regions.sort_by_key(|region| region.name.clone());
+        expected.sort_by_key(|region| region.name.clone());
+        assert!(regions == expected);
+    }
+
+    #[test]
+    fn removing_a_region_keeps_the_others_with_the_same_name() {
+        let old = map_with_regions(&[region("", 1, 0), region("", 2, 0), region("", 3, 0)]);
+        let new = map_with_regions(&[region("", 1, 0)]);
+
+        let diff = MapDiff::new(&old, &new);
+        assert_eq!(diff.regions.len(), 2);
+
+        let map = apply_patch(&old, &new);
+        assert!(map.regions().eq(new.regions()));
+        assert_eq!(map.region_index.regions_at(1, 0), [0]);
+    }
+
+    #[test]
+    fn changed_region_with_a_shared_name_conflicts() {
+        let old = map_with_regions(&[region("a", 1, 0), region("a", 2, 0)]);
+        let new = map_with_regions(&[region("a", 1, 0), region("a", 3, 0)]);
+        let diff = MapDiff::new(&old, &new);
+
+        let mut diverged = map_with_regions(&[region("a", 1, 0), region("a", 4, 0)]);
+        assert_eq!(diff.conflicts(&diverged), [Conflict::Region(b"a".to_vec())]);
+        assert!(diff.apply(&mut diverged).is_err());
+
+        // The first region with the name isn't part of the change, so it can differ.
+        let mut other_first = map_with_regions(&[region("a", 5, 0), region("a", 2, 0)]);
+        diff.apply(&mut other_first).unwrap();
+        assert!(
+            other_first
+                .regions()
+                .eq([&region("a", 5, 0), &region("a", 3, 0)])
+        );
+    }
+
+    // Makes a copy of the map with random changes to the tiles, attributes and regions.
+    fn changed_copy(rng: &mut Rng, map: &Map) -> Map {
+        let mut copy = Map::from_bytes(&map.to_bytes(true)).unwrap();
+
+        for _ in 0..rng.below(500) {
+            let index = rng.below(1024 * 1024) as usize;
+            copy.tiles[index] = rng.below(191) as TileId;
+        }
+
+        match rng.below(3) {
+            0 => copy.attributes_mut().set("NAME", "changed"),
+            1 => copy.attributes_mut().remove("NAME"),
+            _ => copy.attributes_mut().set("VERSION", "2"),
+        }
+
+        let region_indices: Vec<usize> = copy
+            .elvl
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| matches!(chunk, elvl::Chunk::Region(_)))
+            .map(|(index, _)| index)
+            .collect();
+
+        if !region_indices.is_empty() {
+            let index = region_indices[rng.below(region_indices.len() as u64) as usize];
+
+            if rng.below(2) == 0 {
+                copy.elvl.remove(index);
+            } else if let elvl::Chunk::Region(region) = &mut copy.elvl[index] {
+                add_random_spans(rng, region);
+                region.flags ^= elvl::RegionFlags::Base;
+            }
+        }
+
+        let mut region = elvl::Region::empty();
+        region.name = format!("region {}", rng.below(3)).into_bytes();
+        add_random_spans(rng, &mut region);
+        copy.elvl.push(elvl::Chunk::Region(region));
+
+        copy.rebuild_region_index();
+        copy
+    }
+
+    #[test]
+    fn patches_turn_the_old_map_into_the_new_one() {
+        let mut rng = Rng::new(0x1234_5678_9abc_def0);
+
+        for _ in 0..10 {
+            let old = random_map(&mut rng);
+            let new = changed_copy(&mut rng, &old);
+
+            let map = apply_patch(&old, &new);
+            assert!(map.tiles == new.tiles);
+            assert!(map.regions().eq(new.regions()));
+
+            let attributes = |map: &Map| {
+                map.get_attributes()
+                    .iter()
+                    .map(|attribute| (attribute.key.clone(), attribute.value.clone()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(attributes(&map), attributes(&new));
+        }
+    }
+
+    #[test]
+    fn conflicting_patch_changes_nothing() {
+        let old = map_with_regions(&[region("a", 1, 0)]);
+        let mut new = map_with_regions(&[region("a", 2, 0)]);
+        new.tiles[0] = 1;
+
+        let mut map = map_with_regions(&[region("a", 3, 0)]);
+        map.tiles[0] = 2;
+        let before = map.to_bytes(true);
+
+        let diff = MapDiff::new(&old, &new);
+        assert_eq!(
+            diff.conflicts(&map),
+            [
+                Conflict::Tile {
+                    x: 0,
+                    y: 0,
+                    expected: 0,
+                    found: 2
+                },
+                Conflict::Region(b"a".to_vec())
+            ]
+        );
+        assert!(diff.apply(&mut map).is_err());
+        assert_eq!(map.to_bytes(true), before);
+    }
+
+    #[test]
+    fn truncated_patches_are_errors_or_keep_whole_records() {
+        let mut rng = Rng::new(0x7777_1111_2222_3333);
+        let old = random_map(&mut rng);
+        let new = changed_copy(&mut rng, &old);
+        let patch = MapDiff::new(&old, &new).to_bytes();
+
+        // Records run to the end of the file, so a patch cut between records is still a patch with fewer changes.
+        for length in 0..patch.len() {
+            if let Result::Ok(diff) = MapDiff::from_bytes(&patch[..length]) {
+                assert_eq!(diff.to_bytes(), patch[..length]);
+            }
+        }
+
+        assert!(MapDiff::from_bytes(&patch[..PATCH_MAGIC.len() + 3]).is_err());
+    }
+}
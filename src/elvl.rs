@@ -1,8 +1,31 @@
-use anyhow::*;
-use bit_set::BitSet;
+use std::collections::HashMap;
 
 const METADATA_HEADER_SIZE: usize = 12;
 const CHUNK_HEADER_SIZE: usize = 8;
+const REGION_CHUNK_HEADER_SIZE: usize = 8;
+
+const ELVL_MAGIC: u32 = 0x6c766c65;
+
+const CHUNK_KIND_ATTR: u32 = 0x52545441;
+const CHUNK_KIND_REGN: u32 = 0x4E474552;
+const CHUNK_KIND_TSET: u32 = 0x54455354;
+const CHUNK_KIND_TILE: u32 = 0x454C4954;
+const CHUNK_KIND_DCID: u32 = 0x44494344;
+const CHUNK_KIND_DCWT: u32 = 0x54574344;
+const CHUNK_KIND_DCTT: u32 = 0x54544344;
+const CHUNK_KIND_DCBM: u32 = 0x4D424344;
+const CHUNK_KIND_DCLV: u32 = 0x564C4344;
+
+const REGION_KIND_NAME: u32 = 0x4D414E72;
+const REGION_KIND_TILES: u32 = 0x4C495472;
+const REGION_KIND_BASE: u32 = 0x45534272;
+const REGION_KIND_NO_ANTIWARP: u32 = 0x57414E72;
+const REGION_KIND_NO_WEAPONS: u32 = 0x50574E72;
+const REGION_KIND_NO_FLAGS: u32 = 0x4C464E72;
+const REGION_KIND_AUTOWARP: u32 = 0x50574172;
+const REGION_KIND_PYTHON_CODE: u32 = 0x43595072;
+
+const AUTOWARP_ARENA_NAME_SIZE: usize = 16;
 
 struct MetadataHeader {
     magic: u32,
@@ -32,9 +55,134 @@ impl ChunkHeader {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElvlErrorKind {
+    // The data ends before the chunk or sequence does.
+    Truncated,
+    // The attribute doesn't contain a '=' between the key and value.
+    MissingAttributeSeparator,
+    // A region tile sequence goes past the edge of the map.
+    TileOutOfBounds,
+    // A region tile sequence repeats the last row before any row was read.
+    RepeatWithoutRow,
+}
+
+impl std::fmt::Display for ElvlErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            ElvlErrorKind::Truncated => "unexpected end of data",
+            ElvlErrorKind::MissingAttributeSeparator => "attribute did not have key value split",
+            ElvlErrorKind::TileOutOfBounds => "region tiles outside of the map",
+            ElvlErrorKind::RepeatWithoutRow => "region repeats a row before the first row",
+        };
+
+        f.write_str(description)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElvlError {
+    pub kind: ElvlErrorKind,
+    // Kind of the chunk or region sub-chunk that failed to parse.
+    pub chunk_kind: u32,
+    // Byte offset of the failure. This is relative to the start of the file data when returned from elvl_read.
+    pub offset: usize,
+}
+
+impl ElvlError {
+    pub fn new(kind: ElvlErrorKind, chunk_kind: u32, offset: usize) -> Self {
+        Self {
+            kind,
+            chunk_kind,
+            offset,
+        }
+    }
+
+    // Returns the four character name of the chunk kind, such as "REGN".
+    pub fn chunk_name(&self) -> String {
+        self.chunk_kind
+            .to_le_bytes()
+            .iter()
+            .map(|c| {
+                if c.is_ascii_graphic() {
+                    *c as char
+                } else {
+                    '?'
+                }
+            })
+            .collect()
+    }
+
+    fn offset_by(mut self, base: usize) -> Self {
+        self.offset += base;
+        self
+    }
+}
+
+impl std::fmt::Display for ElvlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} in {} chunk at offset {}",
+            self.kind,
+            self.chunk_name(),
+            self.offset
+        )
+    }
+}
+
+impl std::error::Error for ElvlError {}
+
+// Decodes text from the map for display. Text is stored as raw bytes since older maps were made with Windows-1252
+// text, so anything that isn't valid UTF-8 is decoded as Windows-1252.
+pub fn decode_text(data: &[u8]) -> std::borrow::Cow<'_, str> {
+    match std::str::from_utf8(data) {
+        Ok(text) => std::borrow::Cow::Borrowed(text),
+        Err(_) => std::borrow::Cow::Owned(decode_windows_1252(data)),
+    }
+}
+
+pub fn decode_windows_1252(data: &[u8]) -> String {
+    // Characters for 0x80 to 0x9F. Everything else maps directly to the same Unicode code point.
+    // The unassigned bytes are kept as the control code with the same value.
+    const HIGH_CHARACTERS: [char; 32] = [
+        '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}',
+        '\u{2021}', '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}',
+        '\u{017D}', '\u{008F}', '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}',
+        '\u{2022}', '\u{2013}', '\u{2014}', '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}',
+        '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+    ];
+
+    data.iter()
+        .map(|c| match c {
+            0x80..=0x9F => HIGH_CHARACTERS[(c - 0x80) as usize],
+            _ => *c as char,
+        })
+        .collect()
+}
+
+// Keys and values are kept as the raw bytes from the map so they are written back unchanged.
+#[derive(Clone)]
 pub struct Attribute {
-    pub key: String,
-    pub value: String,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl Attribute {
+    pub fn new(key: &str, value: &str) -> Self {
+        Self {
+            key: key.as_bytes().to_owned(),
+            value: value.as_bytes().to_owned(),
+        }
+    }
+
+    pub fn key_text(&self) -> std::borrow::Cow<'_, str> {
+        decode_text(&self.key)
+    }
+
+    pub fn value_text(&self) -> std::borrow::Cow<'_, str> {
+        decode_text(&self.value)
+    }
 }
 
 #[allow(nonstandard_style)]
@@ -45,48 +193,752 @@ pub mod RegionFlags {
     pub const NoFlags: u32 = 1 << 3;
 }
 
+#[derive(Clone, PartialEq, Eq)]
+pub struct AutoWarp {
+    // A coordinate of -1 or 0 keeps the player's current coordinate on that axis.
+    pub x: i16,
+    pub y: i16,
+    pub arena: Option<Vec<u8>>,
+}
+
+impl AutoWarp {
+    pub fn parse(data: &[u8]) -> Result<Self, ElvlError> {
+        if data.len() < 4 {
+            return Err(ElvlError::new(
+                ElvlErrorKind::Truncated,
+                REGION_KIND_AUTOWARP,
+                0,
+            ));
+        }
+
+        let x = i16::from_le_bytes(data[0..2].try_into().unwrap());
+        let y = i16::from_le_bytes(data[2..4].try_into().unwrap());
+
+        let mut arena = None;
+
+        if data.len() >= 4 + AUTOWARP_ARENA_NAME_SIZE {
+            let name = &data[4..4 + AUTOWARP_ARENA_NAME_SIZE];
+            let name_len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+
+            if name_len > 0 {
+                arena = Some(name[..name_len].to_owned());
+            }
+        }
+
+        Ok(Self { x, y, arena })
+    }
+
+    // Returns the destination x tile or None if the player's x coordinate is kept.
+    pub fn destination_x(&self) -> Option<u16> {
+        if self.x > 0 {
+            Some(self.x as u16)
+        } else {
+            None
+        }
+    }
+
+    // Returns the destination y tile or None if the player's y coordinate is kept.
+    pub fn destination_y(&self) -> Option<u16> {
+        if self.y > 0 {
+            Some(self.y as u16)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(4 + AUTOWARP_ARENA_NAME_SIZE);
+
+        data.extend_from_slice(&self.x.to_le_bytes());
+        data.extend_from_slice(&self.y.to_le_bytes());
+
+        // The short form is used when the warp stays in the same arena.
+        if let Some(name) = &self.arena {
+            let name_len = name.len().min(AUTOWARP_ARENA_NAME_SIZE);
+
+            data.extend_from_slice(&name[..name_len]);
+            data.resize(4 + AUTOWARP_ARENA_NAME_SIZE, 0);
+        }
+
+        data
+    }
+}
+
+// Python code that the server runs for the region. The payload is kept as is so it's written back unchanged.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PythonCode {
+    pub payload: Vec<u8>,
+}
+
+impl PythonCode {
+    pub fn new(text: &str) -> Self {
+        Self {
+            payload: text.as_bytes().to_owned(),
+        }
+    }
+
+    pub fn text(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.payload = text.as_bytes().to_owned();
+    }
+}
+
+// A horizontal run of tiles from start up to, but not including, end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Span {
+    pub fn len(&self) -> u16 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+}
+
+// Tiles stored as sorted spans for each row. Touching spans are always merged, so each row has the fewest spans
+// possible and a tile lookup is a binary search within its row.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegionTiles {
+    rows: Vec<Vec<Span>>,
+}
+
+impl RegionTiles {
+    pub fn new() -> Self {
+        Self { rows: vec![] }
+    }
+
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        let row = self.row(y);
+        let index = row.partition_point(|span| span.end <= x);
+
+        index < row.len() && row[index].start <= x
+    }
+
+    // Adds the tiles from start up to end on row y and returns how many of them weren't already set.
+    // Tiles outside of the map are left out.
+    pub fn insert_span(&mut self, y: u16, start: u16, end: u16) -> u32 {
+        let end = end.min(1024);
+
+        if y >= 1024 || start >= end {
+            return 0;
+        }
+
+        if self.rows.len() <= y as usize {
+            self.rows.resize_with(y as usize + 1, Vec::new);
+        }
+
+        let row = &mut self.rows[y as usize];
+
+        // Every span that overlaps or touches the new one gets merged into it.
+        let first = row.partition_point(|span| span.end < start);
+        let last = row.partition_point(|span| span.start <= end);
+
+        let mut merged = Span { start, end };
+        let mut already_set = 0;
+
+        for span in &row[first..last] {
+            already_set += span.end.min(end).saturating_sub(span.start.max(start)) as u32;
+            merged.start = merged.start.min(span.start);
+            merged.end = merged.end.max(span.end);
+        }
+
+        row.splice(first..last, [merged]);
+
+        (end - start) as u32 - already_set
+    }
+
+    pub fn row(&self, y: u16) -> &[Span] {
+        self.rows.get(y as usize).map_or(&[], |row| row.as_slice())
+    }
+
+    // Iterates over every span along with the row it's on, in row order.
+    pub fn spans(&self) -> impl Iterator<Item = (u16, Span)> + '_ {
+        self.rows
+            .iter()
+            .enumerate()
+            .flat_map(|(y, row)| row.iter().map(move |span| (y as u16, *span)))
+    }
+
+    // Iterates over every tile in row order.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.spans()
+            .flat_map(|(y, span)| (span.start..span.end).map(move |x| (x, y)))
+    }
+
+    pub fn len(&self) -> u32 {
+        self.spans().map(|(_, span)| span.len() as u32).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.iter().all(|row| row.is_empty())
+    }
+
+    pub fn union(&self, other: &RegionTiles) -> RegionTiles {
+        self.combine(other, |a, b| a || b)
+    }
+
+    pub fn intersection(&self, other: &RegionTiles) -> RegionTiles {
+        self.combine(other, |a, b| a && b)
+    }
+
+    pub fn difference(&self, other: &RegionTiles) -> RegionTiles {
+        self.combine(other, |a, b| a && !b)
+    }
+
+    // Returns every tile of the map that isn't in this set.
+    pub fn complement(&self) -> RegionTiles {
+        let full_row = [Span {
+            start: 0,
+            end: 1024,
+        }];
+
+        let rows = (0..1024)
+            .map(|y| combine_rows(self.row(y), &full_row, |a, b| !a && b))
+            .collect();
+
+        Self::from_rows(rows)
+    }
+
+    pub fn bounding_box(&self) -> Option<TileRect> {
+        let mut bounds: Option<TileRect> = None;
+
+        for (y, span) in self.spans() {
+            let rect = bounds.get_or_insert(TileRect {
+                left: span.start,
+                top: y,
+                right: span.end,
+                bottom: y + 1,
+            });
+
+            rect.left = rect.left.min(span.start);
+            rect.right = rect.right.max(span.end);
+            rect.bottom = y + 1;
+        }
+
+        bounds
+    }
+
+    // Splits the tiles into groups that are connected to each other.
+    pub fn connected_components(&self, connectivity: Connectivity) -> Vec<RegionTiles> {
+        // Spans are numbered in row order, so every row's spans are a contiguous range of ids.
+        let mut row_starts = Vec::with_capacity(self.rows.len() + 1);
+        let mut span_count = 0;
+
+        for row in &self.rows {
+            row_starts.push(span_count);
+            span_count += row.len();
+        }
+        row_starts.push(span_count);
+
+        let mut parents: Vec<usize> = (0..span_count).collect();
+
+        fn find(parents: &mut [usize], mut id: usize) -> usize {
+            while parents[id] != id {
+                parents[id] = parents[parents[id]];
+                id = parents[id];
+            }
+            id
+        }
+
+        // Diagonal neighbors are connected with 8-connectivity, so spans only need to touch at a corner.
+        let reach = match connectivity {
+            Connectivity::Four => 0,
+            Connectivity::Eight => 1,
+        };
+
+        for y in 1..self.rows.len() {
+            let above = &self.rows[y - 1];
+            let row = &self.rows[y];
+
+            // Both rows are sorted, so walk them together to find the overlapping spans.
+            let (mut i, mut j) = (0, 0);
+
+            while i < above.len() && j < row.len() {
+                let a = above[i];
+                let b = row[j];
+
+                if a.start < b.end + reach && b.start < a.end + reach {
+                    let root_a = find(&mut parents, row_starts[y - 1] + i);
+                    let root_b = find(&mut parents, row_starts[y] + j);
+
+                    parents[root_a] = root_b;
+                }
+
+                if a.end < b.end { i += 1 } else { j += 1 }
+            }
+        }
+
+        let mut components: Vec<RegionTiles> = vec![];
+        let mut component_indices = vec![usize::MAX; span_count];
+
+        for (y, row) in self.rows.iter().enumerate() {
+            for (i, span) in row.iter().enumerate() {
+                let root = find(&mut parents, row_starts[y] + i);
+
+                if component_indices[root] == usize::MAX {
+                    component_indices[root] = components.len();
+                    components.push(RegionTiles::new());
+                }
+
+                let component = &mut components[component_indices[root]];
+
+                component.insert_span(y as u16, span.start, span.end);
+            }
+        }
+
+        components
+    }
+
+    // Traces the boundary of the tiles into polygons in tile corner coordinates.
+    // Tiles that only touch at a corner are given separate polygons that share that corner.
+    pub fn outlines(&self) -> Vec<Polygon> {
+        let mut edges: Vec<((u16, u16), (u16, u16))> = vec![];
+
+        // Edges are directed so the tiles are always on the right side, which makes outer rings clockwise and holes
+        // counterclockwise with y pointing down.
+        for y in 0..self.rows.len() as u16 {
+            let row = self.row(y);
+            let above = if y > 0 { self.row(y - 1) } else { &[] };
+            let below = self.row(y + 1);
+
+            for span in combine_rows(row, above, |a, b| a && !b) {
+                edges.push(((span.start, y), (span.end, y)));
+            }
+
+            for span in combine_rows(row, below, |a, b| a && !b) {
+                edges.push(((span.end, y + 1), (span.start, y + 1)));
+            }
+
+            for span in row {
+                edges.push(((span.end, y), (span.end, y + 1)));
+                edges.push(((span.start, y + 1), (span.start, y)));
+            }
+        }
+
+        let mut outgoing: HashMap<(u16, u16), Vec<usize>> = HashMap::new();
+
+        for (index, (start, _)) in edges.iter().enumerate() {
+            outgoing.entry(*start).or_default().push(index);
+        }
+
+        let direction = |(start, end): ((u16, u16), (u16, u16))| {
+            (
+                (end.0 as i32 - start.0 as i32).signum(),
+                (end.1 as i32 - start.1 as i32).signum(),
+            )
+        };
+
+        let mut used = vec![false; edges.len()];
+        let mut outers: Vec<(Vec<(u16, u16)>, i64)> = vec![];
+        let mut holes: Vec<Vec<(u16, u16)>> = vec![];
+
+        for first in 0..edges.len() {
+            if used[first] {
+                continue;
+            }
+
+            let mut ring = vec![];
+            let mut current = first;
+
+            loop {
+                used[current] = true;
+                ring.push(edges[current].0);
+
+                let (dx, dy) = direction(edges[current]);
+                let candidates = &outgoing[&edges[current].1];
+
+                // Two edges leave a corner where tiles touch diagonally. Turning right stays on the same tile.
+                let next = if candidates.len() == 1 {
+                    candidates[0]
+                } else {
+                    *candidates
+                        .iter()
+                        .find(|index| direction(edges[**index]) == (-dy, dx))
+                        .unwrap_or(&candidates[0])
+                };
+
+                if next == first {
+                    break;
+                }
+
+                current = next;
+            }
+
+            let ring = remove_collinear_points(ring);
+            let area = ring_area(&ring);
+
+            if area > 0 {
+                outers.push((ring, area));
+            } else {
+                holes.push(ring);
+            }
+        }
+
+        let mut polygons: Vec<Polygon> = outers
+            .iter()
+            .map(|(ring, _)| Polygon {
+                outer: ring.clone(),
+                holes: vec![],
+            })
+            .collect();
+
+        for hole in holes {
+            // The tile on the inside of the first edge belongs to the polygon that holds the hole. The smallest
+            // outer ring around it is that polygon, since larger ones may surround an island inside of a hole.
+            // Points are doubled so the tile center doesn't need fractions.
+            let (x, y) = (hole[0].0 as i32, hole[0].1 as i32);
+            let (dx, dy) = direction((hole[0], hole[1]));
+            let point = (x * 2 + dx - dy, y * 2 + dy + dx);
+
+            let owner = outers
+                .iter()
+                .enumerate()
+                .filter(|(_, (ring, _))| ring_contains_doubled(ring, point))
+                .min_by_key(|(_, (_, area))| *area)
+                .map(|(index, _)| index);
+
+            if let Some(owner) = owner {
+                polygons[owner].holes.push(hole);
+            }
+        }
+
+        polygons
+    }
+
+    fn combine(&self, other: &RegionTiles, op: fn(bool, bool) -> bool) -> RegionTiles {
+        let row_count = self.rows.len().max(other.rows.len());
+
+        let rows = (0..row_count as u16)
+            .map(|y| combine_rows(self.row(y), other.row(y), op))
+            .collect();
+
+        Self::from_rows(rows)
+    }
+
+    fn from_rows(mut rows: Vec<Vec<Span>>) -> RegionTiles {
+        while rows.last().is_some_and(|row| row.is_empty()) {
+            rows.pop();
+        }
+
+        Self { rows }
+    }
+}
+
+// Combines two sorted rows of spans, keeping the tiles where op returns true for being in a and being in b.
+fn combine_rows(a: &[Span], b: &[Span], op: fn(bool, bool) -> bool) -> Vec<Span> {
+    let mut edges: Vec<u16> = a
+        .iter()
+        .chain(b.iter())
+        .flat_map(|span| [span.start, span.end])
+        .collect();
+
+    edges.sort_unstable();
+    edges.dedup();
+
+    let contains = |row: &[Span], x: u16| {
+        let index = row.partition_point(|span| span.end <= x);
+        index < row.len() && row[index].start <= x
+    };
+
+    let mut result: Vec<Span> = vec![];
+
+    // Membership can only change at an edge, so each piece between edges is checked once.
+    for piece in edges.windows(2) {
+        let (start, end) = (piece[0], piece[1]);
+
+        if !op(contains(a, start), contains(b, start)) {
+            continue;
+        }
+
+        match result.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => result.push(Span { start, end }),
+        }
+    }
+
+    result
+}
+
+fn remove_collinear_points(ring: Vec<(u16, u16)>) -> Vec<(u16, u16)> {
+    let count = ring.len();
+
+    (0..count)
+        .filter(|i| {
+            let previous = ring[(i + count - 1) % count];
+            let point = ring[*i];
+            let next = ring[(i + 1) % count];
+
+            let vertical = previous.0 == point.0 && point.0 == next.0;
+            let horizontal = previous.1 == point.1 && point.1 == next.1;
+
+            !vertical && !horizontal
+        })
+        .map(|i| ring[i])
+        .collect()
+}
+
+// Signed area of the ring, which is positive for clockwise rings with y pointing down.
+fn ring_area(ring: &[(u16, u16)]) -> i64 {
+    let mut area = 0;
+
+    for (i, a) in ring.iter().enumerate() {
+        let b = ring[(i + 1) % ring.len()];
+
+        area += a.0 as i64 * b.1 as i64 - b.0 as i64 * a.1 as i64;
+    }
+
+    area / 2
+}
+
+// Checks if a point in doubled coordinates is inside of the ring. The point must have odd coordinates so it can never
+// be on the ring.
+fn ring_contains_doubled(ring: &[(u16, u16)], point: (i32, i32)) -> bool {
+    let mut inside = false;
+
+    for (i, a) in ring.iter().enumerate() {
+        let b = ring[(i + 1) % ring.len()];
+
+        // Only vertical edges can cross a horizontal ray.
+        if a.0 != b.0 {
+            continue;
+        }
+
+        let x = a.0 as i32 * 2;
+        let (top, bottom) = (a.1.min(b.1) as i32 * 2, a.1.max(b.1) as i32 * 2);
+
+        if x > point.0 && point.1 > top && point.1 < bottom {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
+
+// A region outline in tile corner coordinates. The outer ring is clockwise and the holes are counterclockwise with
+// y pointing down. Rings are closed implicitly from the last point back to the first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Polygon {
+    pub outer: Vec<(u16, u16)>,
+    pub holes: Vec<Vec<(u16, u16)>>,
+}
+
+impl Polygon {
+    pub fn perimeter(&self) -> u32 {
+        std::iter::once(&self.outer)
+            .chain(self.holes.iter())
+            .map(|ring| ring_perimeter(ring))
+            .sum()
+    }
+
+    // Number of tiles inside of the polygon.
+    pub fn area(&self) -> u32 {
+        let holes: i64 = self.holes.iter().map(|hole| -ring_area(hole)).sum();
+
+        (ring_area(&self.outer) - holes) as u32
+    }
+
+    // Path data for an svg path element. It needs the evenodd fill rule so the holes aren't filled.
+    pub fn to_svg_path(&self) -> String {
+        let mut path = String::new();
+
+        for ring in std::iter::once(&self.outer).chain(self.holes.iter()) {
+            for (i, (x, y)) in ring.iter().enumerate() {
+                let command = if i == 0 { 'M' } else { 'L' };
+                path.push_str(&format!("{}{} {} ", command, x, y));
+            }
+
+            path.push_str("Z ");
+        }
+
+        path.pop();
+        path
+    }
+}
+
+fn ring_perimeter(ring: &[(u16, u16)]) -> u32 {
+    let mut perimeter = 0;
+
+    for (i, a) in ring.iter().enumerate() {
+        let b = ring[(i + 1) % ring.len()];
+
+        perimeter += a.0.abs_diff(b.0) as u32 + a.1.abs_diff(b.1) as u32;
+    }
+
+    perimeter
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity {
+    // Tiles are connected through their edges.
+    Four,
+    // Tiles are connected through their edges and corners.
+    Eight,
+}
+
+// Rectangle of tiles where right and bottom are one past the last tile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileRect {
+    pub left: u16,
+    pub top: u16,
+    pub right: u16,
+    pub bottom: u16,
+}
+
+impl TileRect {
+    pub fn width(&self) -> u16 {
+        self.right - self.left
+    }
+
+    pub fn height(&self) -> u16 {
+        self.bottom - self.top
+    }
+
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        x >= self.left && x < self.right && y >= self.top && y < self.bottom
+    }
+}
+
+#[derive(Clone)]
 pub struct Region {
-    pub name: String,
+    pub name: Vec<u8>,
     pub flags: u32,
-    pub tiles: BitSet,
+    pub tiles: RegionTiles,
     pub tile_count: u32,
+    pub auto_warp: Option<AutoWarp>,
+    pub python_code: Vec<PythonCode>,
+    // Sub-chunks that aren't known, in the order they were read. Each one keeps its position among all of the
+    // region's sub-chunks so it's written back in the same place.
+    // Index, Kind, Payload
+    pub other_chunks: Vec<(usize, u32, Vec<u8>)>,
+    // The REGN payload the region was read from. It's written back as is while the region still matches it, so
+    // regions that weren't changed keep their exact bytes.
+    source: Option<Vec<u8>>,
 }
 
+// Regions are equal when they have the same contents, no matter how they were stored.
+impl PartialEq for Region {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.flags == other.flags
+            && self.tiles == other.tiles
+            && self.auto_warp == other.auto_warp
+            && self.python_code == other.python_code
+            && self.other_chunks == other.other_chunks
+    }
+}
+
+impl Eq for Region {}
+
 impl Region {
     pub fn empty() -> Self {
         Self {
-            name: String::new(),
+            name: vec![],
             flags: 0,
-            tiles: BitSet::new(),
+            tiles: RegionTiles::new(),
             tile_count: 0,
+            auto_warp: None,
+            python_code: vec![],
+            other_chunks: vec![],
+            source: None,
         }
     }
-    pub fn set_tile(&mut self, x: u16, y: u16) {
-        let index = Self::get_index(x, y);
 
-        if self.tiles.insert(index) {
-            self.tile_count += 1;
-        }
+    pub fn name_text(&self) -> std::borrow::Cow<'_, str> {
+        decode_text(&self.name)
+    }
+
+    pub fn set_tile(&mut self, x: u16, y: u16) {
+        self.set_span(y, x, x.saturating_add(1));
+    }
+
+    // Sets the tiles from start up to end on row y. Tiles outside of the map are ignored.
+    pub fn set_span(&mut self, y: u16, start: u16, end: u16) {
+        self.tile_count += self.tiles.insert_span(y, start, end);
+    }
+
+    pub fn in_region(&self, x: u16, y: u16) -> bool {
+        self.tiles.contains(x, y)
+    }
+
+    pub fn get_tiles(&self) -> Vec<(u16, u16)> {
+        self.tiles.iter().collect()
+    }
+
+    // Creates an unnamed region without flags from the tiles.
+    pub fn from_tiles(tiles: RegionTiles) -> Self {
+        let mut region = Self::empty();
+
+        region.tile_count = tiles.len();
+        region.tiles = tiles;
+
+        region
+    }
+
+    // The set operations return new unnamed regions without flags, so they can be named and written as needed.
+    pub fn union(&self, other: &Region) -> Region {
+        Self::from_tiles(self.tiles.union(&other.tiles))
+    }
+
+    pub fn intersection(&self, other: &Region) -> Region {
+        Self::from_tiles(self.tiles.intersection(&other.tiles))
     }
 
-    pub fn in_region(&self, x: u16, y: u16) -> bool {
-        let index = Self::get_index(x, y);
+    pub fn difference(&self, other: &Region) -> Region {
+        Self::from_tiles(self.tiles.difference(&other.tiles))
+    }
 
-        self.tiles.contains(index)
+    pub fn complement(&self) -> Region {
+        Self::from_tiles(self.tiles.complement())
     }
 
-    pub fn get_tiles(&self) -> Vec<(u16, u16)> {
+    pub fn bounding_box(&self) -> Option<TileRect> {
+        self.tiles.bounding_box()
+    }
+
+    // Updates tile_count from the tiles, which is needed after modifying the tiles directly.
+    pub fn recompute_tile_count(&mut self) {
+        self.tile_count = self.tiles.len();
+    }
+
+    pub fn connected_components(&self, connectivity: Connectivity) -> Vec<Region> {
         self.tiles
-            .iter()
-            .map(|index| ((index % 1024) as u16, (index / 1024) as u16))
+            .connected_components(connectivity)
+            .into_iter()
+            .map(Self::from_tiles)
             .collect()
     }
 
-    pub fn parse_data(&mut self, data: &[u8], mut coord: (u16, u16)) -> Result<(u16, u16)> {
-        let mut data = &data[..];
+    pub fn outlines(&self) -> Vec<Polygon> {
+        self.tiles.outlines()
+    }
+
+    // Length of the region's boundary in tiles, including the boundaries of any holes.
+    pub fn perimeter(&self) -> u32 {
+        self.outlines()
+            .iter()
+            .map(|polygon| polygon.perimeter())
+            .sum()
+    }
 
-        while !data.is_empty() {
-            let sequence_kind = data[0] >> 5;
+    // Decodes rTIL sequence data starting at coord and returns the coordinate where the next sequence would start.
+    // Error offsets are relative to the start of data.
+    pub fn parse_data(
+        &mut self,
+        data: &[u8],
+        mut coord: (u16, u16),
+    ) -> Result<(u16, u16), ElvlError> {
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let sequence_kind = data[offset] >> 5;
             // This sequence type is based on the first 3 bits.
             // The 1-32 and 1-1024 of the same type are used for optimization since it would require more bits
             // to encode 1024 always. By using 3 bits to determine, the 1-32 can fit in the remaining 5 bits
@@ -95,272 +947,831 @@ impl Region {
             // Since a single tile would be required for the existence of one of these types, it is encoded as
             // +1 from the remaining bit value. That allows 5 bits to be used for 31(32) since 32 wouldn't
             // normally fit.
-            let mut consumed = 1;
-
-            let advance = |mut coord: (u16, u16), run: u16| -> (u16, u16) {
-                coord.0 += run;
-                if coord.0 >= 1024 {
-                    coord.0 = 0;
-                    coord.1 += 1;
+            let error = |kind| ElvlError::new(kind, REGION_KIND_TILES, offset);
+
+            // The odd sequence kinds are the 1-1024 variants that take an extra byte.
+            let (run, consumed) = if sequence_kind & 1 == 0 {
+                (((data[offset] & 0x1F) + 1) as u16, 1)
+            } else {
+                if offset + 2 > data.len() {
+                    return Err(error(ElvlErrorKind::Truncated));
                 }
-                coord
+
+                let run = (((data[offset] as u16 & 3) << 8) | (data[offset + 1] as u16)) + 1;
+
+                (run, 2)
             };
 
             match sequence_kind {
-                0 => {
-                    // 1-32 Empty tiles in a row
-                    let run = ((data[0] & 0x1F) + 1) as u16;
+                0 | 1 => {
+                    // 1-32 or 1-1024 Empty tiles in a row
+                    if coord.1 >= 1024 || coord.0 + run > 1024 {
+                        return Err(error(ElvlErrorKind::TileOutOfBounds));
+                    }
 
-                    coord = advance(coord, run);
-                    consumed = 1;
+                    coord.0 += run;
                 }
-                1 => {
-                    // 1-1024 Empty tiles in a row
-                    if data.len() < 2 {
-                        return Err(anyhow!("unexpected end of data during region tile parsing"));
+                2 | 3 => {
+                    // 1-32 or 1-1024 Present tiles in a row
+                    if coord.1 >= 1024 || coord.0 + run > 1024 {
+                        return Err(error(ElvlErrorKind::TileOutOfBounds));
                     }
 
-                    let run = (((data[0] as u16 & 3) << 8) | (data[1] as u16)) + 1;
+                    self.set_span(coord.1, coord.0, coord.0 + run);
 
-                    coord = advance(coord, run);
-                    consumed = 2;
+                    coord.0 += run;
                 }
-                2 => {
-                    // 1-32 Present tiles in a row
-                    let run = ((data[0] & 0x1F) + 1) as u16;
-
-                    for i in 0..run {
-                        self.set_tile(coord.0 + i, coord.1);
+                4 | 5 => {
+                    // 1-32 or 1-1024 Rows of empty
+                    if coord.1 + run > 1024 {
+                        return Err(error(ElvlErrorKind::TileOutOfBounds));
                     }
 
-                    coord = advance(coord, run);
-                    consumed = 1;
+                    coord.0 = 0;
+                    coord.1 += run;
                 }
-                3 => {
-                    // 1-1024 Present tiles in a row
-                    if data.len() < 2 {
-                        return Err(anyhow!("unexpected end of data during region tile parsing"));
+                _ => {
+                    // Repeat last row 1-32 or 1-1024 times
+                    if coord.1 == 0 {
+                        return Err(error(ElvlErrorKind::RepeatWithoutRow));
+                    }
+
+                    if coord.1 + run > 1024 {
+                        return Err(error(ElvlErrorKind::TileOutOfBounds));
                     }
 
-                    let run = (((data[0] as u16 & 3) << 8) | (data[1] as u16)) + 1;
+                    let last_row = self.tiles.row(coord.1 - 1).to_vec();
 
                     for i in 0..run {
-                        self.set_tile(coord.0 + i, coord.1);
+                        for span in &last_row {
+                            self.set_span(coord.1 + i, span.start, span.end);
+                        }
                     }
 
-                    coord = advance(coord, run);
-                    consumed = 2;
-                }
-                4 => {
-                    // 1-32 Rows of empty
-                    let run = ((data[0] & 0x1F) + 1) as u16;
-
                     coord.0 = 0;
                     coord.1 += run;
-                    consumed = 1;
                 }
-                5 => {
-                    // 1-1024 Rows of empty
-                    if data.len() < 2 {
-                        return Err(anyhow!("unexpected end of data during region tile parsing"));
-                    }
+            }
 
-                    let run = (((data[0] as u16 & 3) << 8) | (data[1] as u16)) + 1;
+            // Runs that complete a row move on to the start of the next one.
+            if coord.0 >= 1024 {
+                coord.0 = 0;
+                coord.1 += 1;
+            }
 
-                    coord.0 = 0;
-                    coord.1 += run;
-                    consumed = 2;
-                }
-                6 => {
-                    // Repeat last row 1-32 times
-                    let run = ((data[0] & 0x1F) + 1) as u16;
+            offset += consumed;
+        }
 
-                    for i in 0..run {
-                        for x in 0..1024 {
-                            if self.in_region(x, coord.1 - 1) {
-                                self.set_tile(x, coord.1 + i);
-                            }
-                        }
-                    }
+        Ok(coord)
+    }
 
-                    coord.0 = 0;
-                    coord.1 += run;
-                    consumed = 1;
+    // Encodes the tiles into rTIL sequence data that can be decoded with parse_data.
+    // The encoded data always covers the full 1024 rows of the map.
+    //
+    // Each row is stored as alternating runs of empty and present tiles. Rows that match the previous row are
+    // stored as a repeat of the last row and fully empty rows are merged into a single rows of empty sequence,
+    // so the output is as compact as the format allows.
+    pub fn encode_data(&self) -> Vec<u8> {
+        let mut data = vec![];
+        let mut empty_rows: u16 = 0;
+        let mut repeated_rows: u16 = 0;
+        let mut last_row: &[Span] = &[];
+
+        for y in 0..1024 {
+            let row = self.tiles.row(y);
+
+            if row.is_empty() {
+                if repeated_rows > 0 {
+                    Self::encode_sequence(&mut data, 6, repeated_rows);
+                    repeated_rows = 0;
                 }
-                7 => {
-                    // Repeat last row 1-1024 times
-                    let run = (((data[0] as u16 & 3) << 8) | (data[1] as u16)) + 1;
 
-                    for i in 0..run {
-                        for x in 0..1024 {
-                            if self.in_region(x, coord.1 - 1) {
-                                self.set_tile(x, coord.1 + i);
-                            }
-                        }
-                    }
+                empty_rows += 1;
+                last_row = row;
+                continue;
+            }
 
-                    coord.0 = 0;
-                    coord.1 += run;
-                    consumed = 2;
+            if empty_rows > 0 {
+                Self::encode_sequence(&mut data, 4, empty_rows);
+                empty_rows = 0;
+            }
+
+            if row == last_row {
+                repeated_rows += 1;
+                continue;
+            }
+
+            if repeated_rows > 0 {
+                Self::encode_sequence(&mut data, 6, repeated_rows);
+                repeated_rows = 0;
+            }
+
+            let mut x = 0;
+
+            for span in row {
+                if span.start > x {
+                    Self::encode_sequence(&mut data, 0, span.start - x);
                 }
-                _ => {}
+
+                Self::encode_sequence(&mut data, 2, span.len());
+                x = span.end;
+            }
+
+            if x < 1024 {
+                Self::encode_sequence(&mut data, 0, 1024 - x);
             }
 
-            data = &data[consumed..];
+            last_row = row;
         }
 
-        Ok(coord)
+        if empty_rows > 0 {
+            Self::encode_sequence(&mut data, 4, empty_rows);
+        }
+
+        if repeated_rows > 0 {
+            Self::encode_sequence(&mut data, 6, repeated_rows);
+        }
+
+        data
+    }
+
+    // Writes a sequence of the given kind, selecting the 1-32 or 1-1024 variant depending on the run length.
+    // The kind must be the 1-32 variant of the sequence type.
+    fn encode_sequence(data: &mut Vec<u8>, kind: u8, run: u16) {
+        let value = run - 1;
+
+        if run <= 32 {
+            data.push((kind << 5) | value as u8);
+        } else {
+            data.push(((kind + 1) << 5) | (value >> 8) as u8);
+            data.push((value & 0xFF) as u8);
+        }
+    }
+}
+
+// A DCME wall tile set. Each entry is the tile used for one combination of the four neighboring walls.
+pub struct WallTileSet {
+    pub tiles: [u8; 16],
+}
+
+pub struct Bookmark {
+    pub name: String,
+    pub x: u16,
+    pub y: u16,
+}
+
+// DCME payloads are only defined by DCME itself, so these return None when the payload doesn't match the layout.
+// Those chunks are kept as Chunk::Other instead so they are still written back unchanged.
+fn parse_dcme_id(payload: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(payload.try_into().ok()?))
+}
+
+// DCWT: Any number of 16 byte wall tile sets.
+fn parse_dcme_wall_tiles(payload: &[u8]) -> Option<Vec<WallTileSet>> {
+    if !payload.len().is_multiple_of(16) {
+        return None;
+    }
+
+    let sets = payload
+        .chunks_exact(16)
+        .map(|set| WallTileSet {
+            tiles: set.try_into().unwrap(),
+        })
+        .collect();
+
+    Some(sets)
+}
+
+// DCTT: One tile id per character code. A tile id of zero means the character has no tile.
+fn parse_dcme_text_tiles(payload: &[u8]) -> Option<Box<[u8; 256]>> {
+    let tiles: [u8; 256] = payload.try_into().ok()?;
+
+    Some(Box::new(tiles))
+}
+
+// DCBM: Any number of bookmarks, each stored as x: u16, y: u16 followed by a null terminated name.
+fn parse_dcme_bookmarks(mut payload: &[u8]) -> Option<Vec<Bookmark>> {
+    let mut bookmarks = vec![];
+
+    while !payload.is_empty() {
+        if payload.len() < 5 {
+            return None;
+        }
+
+        let x = u16::from_le_bytes(payload[0..2].try_into().unwrap());
+        let y = u16::from_le_bytes(payload[2..4].try_into().unwrap());
+        let name_len = payload[4..].iter().position(|c| *c == 0)?;
+        let name = std::str::from_utf8(&payload[4..4 + name_len]).ok()?;
+
+        bookmarks.push(Bookmark {
+            name: name.to_owned(),
+            x,
+            y,
+        });
+
+        payload = &payload[4 + name_len + 1..];
+    }
+
+    Some(bookmarks)
+}
+
+// DCLV: Null terminated file names of the lvz files used with the map.
+fn parse_dcme_lvz(payload: &[u8]) -> Option<Vec<String>> {
+    if payload.last().is_some_and(|c| *c != 0) {
+        return None;
     }
 
-    fn get_index(x: u16, y: u16) -> usize {
-        y as usize * 1024 + x as usize
+    let mut files = vec![];
+
+    if let Some((_, names)) = payload.split_last() {
+        for name in names.split(|c| *c == 0) {
+            files.push(std::str::from_utf8(name).ok()?.to_owned());
+        }
     }
+
+    Some(files)
 }
 
 pub enum Chunk {
     Attribute(Attribute),
     Region(Region),
-    Tileset,
-    Tile,
+    // Tileset bitmap, including the bitmap file header
+    Tileset(Vec<u8>),
+    // Tile data in the same format as the body of the map file
+    Tile(Vec<u8>),
 
     // DCME hash code
     DcmeId(u32),
-    DcmeWallTiles,
-    DcmeTextTiles,
-    DcmeBookmarks,
-    DcmeLvz,
+    DcmeWallTiles(Vec<WallTileSet>),
+    // Tile id for each character code
+    DcmeTextTiles(Box<[u8; 256]>),
+    DcmeBookmarks(Vec<Bookmark>),
+    // Lvz file names
+    DcmeLvz(Vec<String>),
 
     // Kind, Payload
     Other(u32, Vec<u8>),
 }
 
-pub fn elvl_read(data: &[u8]) -> anyhow::Result<Vec<Chunk>> {
-    let mut chunks = vec![];
+fn read_attribute(payload: &[u8], offset: usize) -> Result<Attribute, ElvlError> {
+    let error = |kind| ElvlError::new(kind, CHUNK_KIND_ATTR, offset);
+
+    let mut parts = payload.splitn(2, |c| *c == b'=');
+
+    let key = parts.next();
+    let value = parts.next();
+
+    if let (Some(key), Some(value)) = (key, value) {
+        Ok(Attribute {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+    } else {
+        Err(error(ElvlErrorKind::MissingAttributeSeparator))
+    }
+}
+
+// Reads a region from the payload of a REGN chunk. The offset is where the payload starts and is only used for errors.
+pub fn read_region(payload: &[u8], offset: usize) -> Result<Region, ElvlError> {
+    let mut region = Region::empty();
+
+    let mut region_data = payload;
+    let mut region_offset = offset;
+
+    let mut coord = (0u16, 0u16);
+    let mut sub_chunk_index = 0;
+
+    while region_data.len() >= REGION_CHUNK_HEADER_SIZE {
+        let kind = u32::from_le_bytes(region_data[0..4].try_into().unwrap());
+        let chunk_size = u32::from_le_bytes(region_data[4..8].try_into().unwrap()) as usize;
+        let payload_offset = region_offset + REGION_CHUNK_HEADER_SIZE;
+
+        if chunk_size > region_data.len() - REGION_CHUNK_HEADER_SIZE {
+            return Err(ElvlError::new(
+                ElvlErrorKind::Truncated,
+                kind,
+                region_offset,
+            ));
+        }
+
+        let region_chunk_payload =
+            &region_data[REGION_CHUNK_HEADER_SIZE..REGION_CHUNK_HEADER_SIZE + chunk_size];
+
+        match kind {
+            REGION_KIND_NAME => {
+                // rNAM
+                region.name = region_chunk_payload.to_owned();
+            }
+            REGION_KIND_TILES => {
+                // rTIL
+                coord = region
+                    .parse_data(region_chunk_payload, coord)
+                    .map_err(|e| e.offset_by(payload_offset))?;
+            }
+            REGION_KIND_BASE => {
+                // rBSE
+                region.flags |= RegionFlags::Base;
+            }
+            REGION_KIND_NO_ANTIWARP => {
+                // rNAW
+                region.flags |= RegionFlags::NoAntiwarp;
+            }
+            REGION_KIND_NO_WEAPONS => {
+                // rNWP
+                region.flags |= RegionFlags::NoWeapons;
+            }
+            REGION_KIND_NO_FLAGS => {
+                // rNFL
+                region.flags |= RegionFlags::NoFlags;
+            }
+            REGION_KIND_AUTOWARP => {
+                // rAWP
+                region.auto_warp = Some(
+                    AutoWarp::parse(region_chunk_payload)
+                        .map_err(|e| e.offset_by(payload_offset))?,
+                );
+            }
+            REGION_KIND_PYTHON_CODE => {
+                // rPYC
+                region.python_code.push(PythonCode {
+                    payload: region_chunk_payload.to_owned(),
+                });
+            }
+            _ => {
+                region
+                    .other_chunks
+                    .push((sub_chunk_index, kind, region_chunk_payload.to_owned()));
+            }
+        }
+
+        // The padding of the last sub-chunk may be missing, so don't go past the end.
+        let total_chunk_size = REGION_CHUNK_HEADER_SIZE + ((chunk_size + 3) & !3);
+        let total_chunk_size = total_chunk_size.min(region_data.len());
+
+        region_data = &region_data[total_chunk_size..];
+        region_offset += total_chunk_size;
+        sub_chunk_index += 1;
+    }
+
+    region.source = Some(payload.to_owned());
+
+    Ok(region)
+}
+
+// Returns true if the map file data has an eLVL metadata block, even if the block doesn't have any chunks.
+pub fn has_metadata(data: &[u8]) -> bool {
+    find_metadata(data).is_some()
+}
 
+// Finds the offset and header of the metadata block in the map file data.
+fn find_metadata(data: &[u8]) -> Option<(usize, MetadataHeader)> {
     if data.len() < 10 {
-        return Ok(chunks);
+        return None;
     }
 
     // This doesn't have a bitmap header, so it must not contain elvl data.
     if data[0] != b'B' || data[1] != b'M' {
-        return Ok(chunks);
+        return None;
     }
 
     let metadata_offset = u32::from_le_bytes(data[6..10].try_into().unwrap()) as usize;
     if metadata_offset == 0 {
-        return Ok(chunks);
+        return None;
     }
 
-    if data.len() < metadata_offset + METADATA_HEADER_SIZE {
-        // This isn't a valid elvl file, so ignore it. No error because map files don't need elvl sections.
-        return Ok(chunks);
+    // Without a full header this isn't a valid elvl file, so ignore it. No error because map files don't need elvl
+    // sections.
+    let header = data.get(metadata_offset..metadata_offset + METADATA_HEADER_SIZE)?;
+    let header = MetadataHeader::new(header.try_into().unwrap());
+
+    if header.magic != ELVL_MAGIC {
+        return None;
     }
 
-    let header = MetadataHeader::new(
-        data[metadata_offset..metadata_offset + METADATA_HEADER_SIZE]
-            .try_into()
-            .unwrap(),
-    );
+    Some((metadata_offset, header))
+}
 
-    if header.magic != 0x6c766c65 {
-        // This isn't a valid elvl file, so ignore it. No error because map files don't need elvl sections.
-        return Ok(chunks);
-    }
+// Reads the chunks from the metadata of the map file data.
+// Chunks that fail to parse are returned as warnings and kept as Chunk::Other, so writing the chunks back doesn't lose
+// them.
+pub fn elvl_read(data: &[u8]) -> (Vec<Chunk>, Vec<ElvlError>) {
+    let (chunks, warnings) = elvl_read_with_offsets(data);
+
+    (
+        chunks.into_iter().map(|(_, chunk)| chunk).collect(),
+        warnings,
+    )
+}
+
+// Same as elvl_read, but each chunk comes with the file offset of its payload.
+pub fn elvl_read_with_offsets(data: &[u8]) -> (Vec<(usize, Chunk)>, Vec<ElvlError>) {
+    let mut chunks = vec![];
+    let mut warnings = vec![];
+
+    let Some((metadata_offset, header)) = find_metadata(data) else {
+        return (chunks, warnings);
+    };
 
     let mut data = &data[metadata_offset + METADATA_HEADER_SIZE..];
     let mut consumed: usize = METADATA_HEADER_SIZE;
 
     while data.len() >= CHUNK_HEADER_SIZE && consumed < header.total_size as usize {
         let chunk_header = ChunkHeader::new(data[0..CHUNK_HEADER_SIZE].try_into().unwrap());
+        let chunk_offset = metadata_offset + consumed;
+        let payload_offset = chunk_offset + CHUNK_HEADER_SIZE;
+
+        if chunk_header.size as usize > data.len() - CHUNK_HEADER_SIZE {
+            // The chunk sizes can't be trusted anymore, so nothing after this can be read.
+            warnings.push(ElvlError::new(
+                ElvlErrorKind::Truncated,
+                chunk_header.kind,
+                chunk_offset,
+            ));
+            break;
+        }
+
         let payload = &data[CHUNK_HEADER_SIZE..CHUNK_HEADER_SIZE + chunk_header.size as usize];
 
         let chunk = match chunk_header.kind {
-            0x52545441 => {
-                // ATTR
-                let mut parts = payload.splitn(2, |c| *c == b'=');
+            CHUNK_KIND_ATTR => read_attribute(payload, payload_offset).map(Chunk::Attribute),
+            CHUNK_KIND_REGN => read_region(payload, payload_offset).map(Chunk::Region),
+            CHUNK_KIND_TSET => Ok(Chunk::Tileset(payload.to_owned())),
+            CHUNK_KIND_TILE => Ok(Chunk::Tile(payload.to_owned())),
+            CHUNK_KIND_DCID if let Some(id) = parse_dcme_id(payload) => Ok(Chunk::DcmeId(id)),
+            CHUNK_KIND_DCWT if let Some(sets) = parse_dcme_wall_tiles(payload) => {
+                Ok(Chunk::DcmeWallTiles(sets))
+            }
+            CHUNK_KIND_DCTT if let Some(tiles) = parse_dcme_text_tiles(payload) => {
+                Ok(Chunk::DcmeTextTiles(tiles))
+            }
+            CHUNK_KIND_DCBM if let Some(bookmarks) = parse_dcme_bookmarks(payload) => {
+                Ok(Chunk::DcmeBookmarks(bookmarks))
+            }
+            CHUNK_KIND_DCLV if let Some(files) = parse_dcme_lvz(payload) => {
+                Ok(Chunk::DcmeLvz(files))
+            }
+            _ => Ok(Chunk::Other(chunk_header.kind, payload.to_owned())),
+        };
+
+        match chunk {
+            Ok(chunk) => chunks.push((payload_offset, chunk)),
+            Err(e) => {
+                warnings.push(e);
+                chunks.push((
+                    payload_offset,
+                    Chunk::Other(chunk_header.kind, payload.to_owned()),
+                ));
+            }
+        }
+
+        // Align data to 4 bytes. The padding of the last chunk may be missing, so don't go past the end.
+        let total_chunk_size = CHUNK_HEADER_SIZE + ((chunk_header.size as usize + 3) & !3);
+        let total_chunk_size = total_chunk_size.min(data.len());
+
+        data = &data[total_chunk_size..];
+        consumed += total_chunk_size;
+    }
+
+    (chunks, warnings)
+}
 
-                let key = parts.next();
-                let value = parts.next();
+pub fn write_chunk(out: &mut Vec<u8>, kind: u32, payload: &[u8]) {
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
 
-                if let (Some(key), Some(value)) = (key, value) {
-                    let key = std::str::from_utf8(key)?;
-                    let value = std::str::from_utf8(value)?;
+    // Align data to 4 bytes
+    out.resize((out.len() + 3) & !3, 0);
+}
 
-                    let data = Attribute {
-                        key: key.to_owned(),
-                        value: value.to_owned(),
-                    };
+// Writes the payload of a REGN chunk for the region. Regions that weren't changed since they were read are written
+// exactly as they were read.
+pub fn write_region(region: &Region) -> Vec<u8> {
+    if let Some(source) = &region.source
+        && read_region(source, 0).is_ok_and(|original| original == *region)
+    {
+        return source.clone();
+    }
 
-                    Chunk::Attribute(data)
-                } else {
-                    return Err(anyhow!("attribute data did not have key value split"));
+    // Kind, Payload
+    let mut sub_chunks: Vec<(u32, std::borrow::Cow<'_, [u8]>)> = vec![];
+
+    if !region.name.is_empty() {
+        sub_chunks.push((REGION_KIND_NAME, region.name.as_slice().into()));
+    }
+
+    sub_chunks.push((REGION_KIND_TILES, region.encode_data().into()));
+
+    let flag_kinds = [
+        (RegionFlags::Base, REGION_KIND_BASE),
+        (RegionFlags::NoAntiwarp, REGION_KIND_NO_ANTIWARP),
+        (RegionFlags::NoWeapons, REGION_KIND_NO_WEAPONS),
+        (RegionFlags::NoFlags, REGION_KIND_NO_FLAGS),
+    ];
+
+    for (flag, kind) in flag_kinds {
+        if region.flags & flag != 0 {
+            sub_chunks.push((kind, [].as_slice().into()));
+        }
+    }
+
+    if let Some(auto_warp) = &region.auto_warp {
+        sub_chunks.push((REGION_KIND_AUTOWARP, auto_warp.to_bytes().into()));
+    }
+
+    for code in &region.python_code {
+        sub_chunks.push((REGION_KIND_PYTHON_CODE, code.payload.as_slice().into()));
+    }
+
+    // Unknown sub-chunks go back to the position they were read at. They are in index order, so each one lands at its
+    // index unless the region has fewer known sub-chunks than when it was read.
+    for (index, kind, chunk_payload) in &region.other_chunks {
+        let index = (*index).min(sub_chunks.len());
+        sub_chunks.insert(index, (*kind, chunk_payload.as_slice().into()));
+    }
+
+    let mut payload = vec![];
+
+    for (kind, chunk_payload) in &sub_chunks {
+        write_chunk(&mut payload, *kind, chunk_payload);
+    }
+
+    payload
+}
+
+// Serializes the chunks into an eLVL metadata block, including the metadata header.
+pub fn elvl_write<'a>(chunks: impl IntoIterator<Item = &'a Chunk>) -> Vec<u8> {
+    let mut out = vec![0; METADATA_HEADER_SIZE];
+
+    for chunk in chunks {
+        match chunk {
+            Chunk::Attribute(attr) => {
+                let mut payload = Vec::with_capacity(attr.key.len() + attr.value.len() + 1);
+
+                payload.extend_from_slice(&attr.key);
+                payload.push(b'=');
+                payload.extend_from_slice(&attr.value);
+
+                write_chunk(&mut out, CHUNK_KIND_ATTR, &payload);
+            }
+            Chunk::Region(region) => {
+                write_chunk(&mut out, CHUNK_KIND_REGN, &write_region(region));
+            }
+            Chunk::DcmeId(id) => {
+                write_chunk(&mut out, CHUNK_KIND_DCID, &id.to_le_bytes());
+            }
+            Chunk::DcmeWallTiles(sets) => {
+                let payload: Vec<u8> = sets.iter().flat_map(|set| set.tiles).collect();
+
+                write_chunk(&mut out, CHUNK_KIND_DCWT, &payload);
+            }
+            Chunk::DcmeTextTiles(tiles) => {
+                write_chunk(&mut out, CHUNK_KIND_DCTT, tiles.as_slice());
+            }
+            Chunk::DcmeBookmarks(bookmarks) => {
+                let mut payload = vec![];
+
+                for bookmark in bookmarks {
+                    payload.extend_from_slice(&bookmark.x.to_le_bytes());
+                    payload.extend_from_slice(&bookmark.y.to_le_bytes());
+                    payload.extend_from_slice(bookmark.name.as_bytes());
+                    payload.push(0);
+                }
+
+                write_chunk(&mut out, CHUNK_KIND_DCBM, &payload);
+            }
+            Chunk::DcmeLvz(files) => {
+                let mut payload = vec![];
+
+                for file in files {
+                    payload.extend_from_slice(file.as_bytes());
+                    payload.push(0);
                 }
+
+                write_chunk(&mut out, CHUNK_KIND_DCLV, &payload);
+            }
+            Chunk::Other(kind, payload) => {
+                write_chunk(&mut out, *kind, payload);
+            }
+            Chunk::Tileset(bitmap) => {
+                write_chunk(&mut out, CHUNK_KIND_TSET, bitmap);
             }
-            0x4E474552 => {
-                // REGN
-                let mut region = Region::empty();
+            Chunk::Tile(tiledata) => {
+                write_chunk(&mut out, CHUNK_KIND_TILE, tiledata);
+            }
+        }
+    }
 
-                let mut region_data = &payload[..];
+    let total_size = out.len() as u32;
 
-                const REGION_CHUNK_HEADER_SIZE: usize = 8;
+    out[0..4].copy_from_slice(&ELVL_MAGIC.to_le_bytes());
+    out[4..8].copy_from_slice(&total_size.to_le_bytes());
 
-                let mut coord = (0u16, 0u16);
+    out
+}
 
-                while region_data.len() > REGION_CHUNK_HEADER_SIZE {
-                    let kind = u32::from_le_bytes(region_data[0..4].try_into().unwrap());
-                    let chunk_size =
-                        u32::from_le_bytes(region_data[4..8].try_into().unwrap()) as usize;
-                    let region_chunk_payload = &region_data[8..8 + chunk_size];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{BITMAP_FILE_HEADER_SIZE, Rng, chunk, map_file, metadata};
 
-                    match kind {
-                        0x4D414E72 => {
-                            // rNAM
-                            region.name = std::str::from_utf8(region_chunk_payload)
-                                .unwrap()
-                                .to_owned();
-                        }
-                        0x4C495472 => {
-                            // rTIL
-                            coord = region.parse_data(region_chunk_payload, coord)?;
-                        }
-                        0x45534272 => {
-                            // rBSE
-                            region.flags |= RegionFlags::Base;
-                        }
-                        0x57414E72 => {
-                            // rNAW
-                            region.flags |= RegionFlags::NoAntiwarp;
-                        }
-                        0x50574E72 => {
-                            // rNWP
-                            region.flags |= RegionFlags::NoWeapons;
-                        }
-                        0x4C464E72 => {
-                            // rNFL
-                            region.flags |= RegionFlags::NoFlags;
-                        }
-                        // TODO: rAWP, rPYC
-                        _ => {}
-                    }
+    // Builds a map file with a single region that only has the rTIL data.
+    fn region_tiles_file(tiles: &[u8]) -> Vec<u8> {
+        map_file(&metadata(&chunk(b"REGN", &chunk(b"rTIL", tiles))), &[])
+    }
 
-                    let total_chunk_size =
-                        REGION_CHUNK_HEADER_SIZE + ((chunk_size as usize + 3) & !3);
-                    region_data = &region_data[total_chunk_size..];
-                }
+    // File offset of the rTIL data in region_tiles_file.
+    const TILES_OFFSET: usize = BITMAP_FILE_HEADER_SIZE
+        + METADATA_HEADER_SIZE
+        + CHUNK_HEADER_SIZE
+        + REGION_CHUNK_HEADER_SIZE;
+
+    fn random_region(rng: &mut Rng, number: u64) -> Region {
+        let mut region = Region::empty();
+
+        region.name = [format!("region {} ", number).as_bytes(), &rng.bytes(8)].concat();
+        region.flags = rng.below(16) as u32;
+
+        for _ in 0..rng.below(10) {
+            let y = rng.below(1024) as u16;
+            let start = rng.below(1024) as u16;
 
-                Chunk::Region(region)
+            // Mostly short runs, with some long enough to need the 1-1024 sequences.
+            let length = if rng.below(4) == 0 {
+                rng.below(1024)
+            } else {
+                rng.below(32)
+            };
+            let end = (start + length as u16).min(1024);
+
+            // Repeating the run on following rows gives the encoder rows to repeat.
+            for row in y..(y + rng.below(4) as u16 + 1).min(1024) {
+                for x in start..end {
+                    region.set_tile(x, row);
+                }
             }
-            _ => Chunk::Other(chunk_header.kind, payload.to_owned()),
-        };
+        }
 
-        chunks.push(chunk);
+        if rng.below(2) == 0 {
+            region.auto_warp = Some(AutoWarp {
+                x: rng.next() as i16,
+                y: rng.next() as i16,
+                arena: None,
+            });
+        }
 
-        // Align data to 4 bytes
-        let total_chunk_size = CHUNK_HEADER_SIZE + ((chunk_header.size as usize + 3) & !3);
+        for _ in 0..rng.below(3) {
+            region.python_code.push(PythonCode::new("print('hello')"));
+        }
 
-        data = &data[total_chunk_size..];
-        consumed += total_chunk_size;
+        region
+    }
+
+    fn random_chunks(rng: &mut Rng) -> Vec<Chunk> {
+        (0..rng.below(8))
+            .map(|number| match rng.below(3) {
+                0 => {
+                    let mut key = rng.bytes(8);
+                    key.retain(|c| *c != b'=');
+
+                    Chunk::Attribute(Attribute {
+                        key,
+                        value: rng.bytes(16),
+                    })
+                }
+                1 => Chunk::Region(random_region(rng, number)),
+                // Known chunk kinds start with an uppercase letter, so these are never mistaken for one.
+                _ => Chunk::Other(
+                    u32::from_le_bytes([b'x', b'x', b'x', rng.next() as u8]),
+                    rng.bytes(32),
+                ),
+            })
+            .collect()
+    }
+
+    // Reads a file with a single chunk that fails to parse and returns the warnings.
+    fn read_warnings(data: &[u8]) -> Vec<ElvlError> {
+        let (chunks, warnings) = elvl_read(data);
+
+        // The chunk is kept as it was so it can be written back.
+        assert!(matches!(chunks[..], [Chunk::Other(CHUNK_KIND_REGN, _)]));
+        assert_eq!(map_file(&elvl_write(&chunks), &[]), data);
+
+        warnings
+    }
+
+    #[test]
+    fn written_chunks_read_back_the_same() {
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..20 {
+            let chunks = random_chunks(&mut rng);
+            let written = elvl_write(&chunks);
+            let (read, warnings) = elvl_read(&map_file(&written, &[]));
+
+            assert!(warnings.is_empty(), "{:?}", warnings);
+            assert_eq!(read.len(), chunks.len());
+            assert_eq!(elvl_write(&read), written);
+        }
+    }
+
+    #[test]
+    fn encoded_tiles_decode_to_the_same_tiles() {
+        let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+
+        for number in 0..20 {
+            let region = random_region(&mut rng, number);
+            let mut decoded = Region::empty();
+
+            assert!(decoded.parse_data(&region.encode_data(), (0, 0)).is_ok());
+            assert_eq!(decoded.tile_count, region.tile_count);
+            assert_eq!(decoded.encode_data(), region.encode_data());
+        }
+    }
+
+    #[test]
+    fn changed_files_never_panic() {
+        let mut rng = Rng::new(0xdead_beef_cafe_f00d);
+
+        for _ in 0..100 {
+            let mut data = map_file(&elvl_write(&random_chunks(&mut rng)), &[]);
+            rng.mutate(&mut data);
+
+            // Only panics are failures. Anything else is either read or returned as a warning.
+            let _ = elvl_read(&data);
+        }
+    }
+
+    #[test]
+    fn long_run_without_second_byte_is_truncated() {
+        assert_eq!(
+            read_warnings(&region_tiles_file(&[0b001_00000])),
+            [ElvlError::new(
+                ElvlErrorKind::Truncated,
+                REGION_KIND_TILES,
+                TILES_OFFSET
+            )]
+        );
+    }
+
+    #[test]
+    fn run_past_the_end_of_a_row_is_out_of_bounds() {
+        // One empty tile followed by 1024 present tiles.
+        assert_eq!(
+            read_warnings(&region_tiles_file(&[0b000_00000, 0b011_00011, 0xFF])),
+            [ElvlError::new(
+                ElvlErrorKind::TileOutOfBounds,
+                REGION_KIND_TILES,
+                TILES_OFFSET + 1
+            )]
+        );
+    }
+
+    #[test]
+    fn rows_past_the_end_of_the_map_are_out_of_bounds() {
+        // 1024 empty rows followed by one more.
+        assert_eq!(
+            read_warnings(&region_tiles_file(&[0b101_00011, 0xFF, 0b100_00000])),
+            [ElvlError::new(
+                ElvlErrorKind::TileOutOfBounds,
+                REGION_KIND_TILES,
+                TILES_OFFSET + 2
+            )]
+        );
+    }
+
+    #[test]
+    fn repeating_a_row_before_the_first_row_is_an_error() {
+        assert_eq!(
+            read_warnings(&region_tiles_file(&[0b110_00000])),
+            [ElvlError::new(
+                ElvlErrorKind::RepeatWithoutRow,
+                REGION_KIND_TILES,
+                TILES_OFFSET
+            )]
+        );
     }
 
-    Ok(chunks)
+    #[test]
+    fn truncated_chunk_keeps_the_chunks_before_it() {
+        let attribute = chunk(b"ATTR", b"NAME=test");
+
+        // A chunk header with a size larger than the rest of the data.
+        let mut chunks = attribute.clone();
+        chunks.extend_from_slice(b"ATTR");
+        chunks.extend_from_slice(&100u32.to_le_bytes());
+        chunks.extend_from_slice(b"KEY=");
+
+        let (read, warnings) = elvl_read(&map_file(&metadata(&chunks), &[]));
+
+        assert_eq!(read.len(), 1);
+        assert_eq!(
+            warnings,
+            [ElvlError::new(
+                ElvlErrorKind::Truncated,
+                CHUNK_KIND_ATTR,
+                BITMAP_FILE_HEADER_SIZE + METADATA_HEADER_SIZE + attribute.len()
+            )]
+        );
+    }
 }
@@ -1,7 +1,14 @@
 //#![windows_subsystem = "windows"]
-use crate::{camera::Camera, map::Map};
+use crate::{
+    camera::Camera,
+    diff::MapDiff,
+    map::{Map, SaveOptions},
+    pathfinding::{PathOptions, Pathfinder},
+    reachability::Reachability,
+    walkability::DEFAULT_SHIP_RADIUS,
+};
 use anyhow::*;
-use std::sync::Arc;
+use std::{fs, sync::Arc};
 
 use winit::{
     application::ApplicationHandler,
@@ -12,9 +19,18 @@ use winit::{
 };
 
 pub mod camera;
+pub mod collision;
+pub mod diff;
 pub mod elvl;
 pub mod map;
 pub mod map_renderer;
+pub mod pathfinding;
+pub mod reachability;
+pub mod tileset;
+pub mod walkability;
+
+#[cfg(test)]
+mod testing;
 
 enum Action {
     Drag(PhysicalPosition<f64>),
@@ -33,10 +49,27 @@ struct State {
 
     // TODO: This should probably be moved into some map editor structure.
     action: Option<Action>,
+
+    map: Map,
+    title: String,
+    // Drawn under picked paths.
+    overlay: image::RgbaImage,
+    // Ship radius in pixels for picked paths, the same one the overlay was made with.
+    radius: u16,
+    // Built the first time a path is picked since it takes a moment.
+    pathfinder: Option<Pathfinder>,
+    // The first tile picked for a path, waiting for the second one.
+    path_start: Option<(u16, u16)>,
 }
 
 impl State {
-    async fn new(window: Arc<Window>, map: Map) -> State {
+    async fn new(
+        window: Arc<Window>,
+        map: Map,
+        title: String,
+        overlay: image::RgbaImage,
+        radius: u16,
+    ) -> State {
         let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions::default())
@@ -56,6 +89,7 @@ impl State {
         let mut map_renderer = map_renderer::MapRenderer::new(&device, &surface_format);
 
         map_renderer.set_map(&map, &queue);
+        map_renderer.set_overlay(&overlay, &queue);
 
         let camera = Camera::new(
             size.width as f32,
@@ -75,6 +109,12 @@ impl State {
             camera,
             mouse_position: PhysicalPosition::new(0.0, 0.0),
             action: None,
+            map,
+            title,
+            overlay,
+            radius,
+            pathfinder: None,
+            path_start: None,
         };
 
         state.configure_surface();
@@ -105,28 +145,87 @@ impl State {
 
         self.surface.configure(&self.device, &surface_config);
 
-        return true;
+        true
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) -> bool {
         self.size = new_size;
         self.camera
             .set_surface_dimensions(new_size.width as f32, new_size.height as f32);
-        return self.configure_surface();
+        self.configure_surface()
     }
 
-    fn render(&mut self) -> bool {
-        if self.size.width == 0 || self.size.height == 0 {
-            return false;
+    fn mouse_tile(&self) -> Option<(u16, u16)> {
+        let world = self.camera.unproject(glam::Vec2::new(
+            self.mouse_position.x as f32,
+            self.mouse_position.y as f32,
+        ));
+
+        if world.x < 0.0 || world.y < 0.0 || world.x >= 1024.0 || world.y >= 1024.0 {
+            return None;
         }
 
-        let surface_texture = self.surface.get_current_texture();
+        Some((world.x as u16, world.y as u16))
+    }
+
+    // The first pick sets the start of the path and the second one finds the path to it and draws it.
+    fn pick_path_tile(&mut self) {
+        const PATH_COLOR: image::Rgba<u8> = image::Rgba([255, 220, 0, 200]);
+        const START_COLOR: image::Rgba<u8> = image::Rgba([0, 255, 0, 255]);
+        const GOAL_COLOR: image::Rgba<u8> = image::Rgba([255, 0, 0, 255]);
+
+        let Some(tile) = self.mouse_tile() else {
+            return;
+        };
+
+        let mut overlay = self.overlay.clone();
+
+        let Some(start) = self.path_start.take() else {
+            self.path_start = Some(tile);
+
+            overlay.put_pixel(tile.0 as u32, tile.1 as u32, START_COLOR);
+            self.map_renderer.set_overlay(&overlay, &self.queue);
+            self.window.set_title(&self.title);
+            return;
+        };
+
+        let pathfinder = self.pathfinder.get_or_insert_with(|| {
+            Pathfinder::new(
+                &self.map,
+                &PathOptions {
+                    radius: self.radius,
+                    smooth: true,
+                    ..Default::default()
+                },
+            )
+        });
+
+        let title = match pathfinder.find_path(start, tile) {
+            Some(path) => {
+                for (x, y) in path.tiles() {
+                    overlay.put_pixel(x as u32, y as u32, PATH_COLOR);
+                }
+
+                format!("{} - path {:.1} tiles", self.title, path.length())
+            }
+            None => format!("{} - no path", self.title),
+        };
+
+        overlay.put_pixel(start.0 as u32, start.1 as u32, START_COLOR);
+        overlay.put_pixel(tile.0 as u32, tile.1 as u32, GOAL_COLOR);
+
+        self.map_renderer.set_overlay(&overlay, &self.queue);
+        self.window.set_title(&title);
+    }
 
-        if let Err(_) = surface_texture {
+    fn render(&mut self) -> bool {
+        if self.size.width == 0 || self.size.height == 0 {
             return false;
         }
 
-        let surface_texture = surface_texture.unwrap();
+        let Some(surface_texture) = self.surface.get_current_texture().ok() else {
+            return false;
+        };
 
         let texture_view = surface_texture
             .texture
@@ -180,34 +279,47 @@ impl State {
         // Update to at least 26.0.2 when it's released.
         surface_texture.present();
 
-        return true;
+        true
     }
 }
 
 struct App {
     state: Option<State>,
     map: Option<Map>,
+    title: String,
+    overlay: image::RgbaImage,
+    radius: u16,
 }
 
 impl App {
-    fn new(map: Map) -> App {
+    fn new(map: Map, title: String, overlay: image::RgbaImage, radius: u16) -> App {
         App {
             state: None,
             map: Some(map),
+            title,
+            overlay,
+            radius,
         }
     }
 }
 
 impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        let map = self.map.take().unwrap();
+
         let window = Arc::new(
             event_loop
-                .create_window(Window::default_attributes())
+                .create_window(Window::default_attributes().with_title(&self.title))
                 .unwrap(),
         );
 
-        let map = self.map.take().unwrap();
-        let state = pollster::block_on(State::new(window.clone(), map));
+        let state = pollster::block_on(State::new(
+            window.clone(),
+            map,
+            self.title.clone(),
+            self.overlay.clone(),
+            self.radius,
+        ));
 
         self.state = Some(state);
 
@@ -226,10 +338,8 @@ impl ApplicationHandler for App {
             WindowEvent::CloseRequested => {
                 event_loop.exit();
             }
-            WindowEvent::RedrawRequested => {
-                if app_state.render() {
-                    app_state.get_window().request_redraw();
-                }
+            WindowEvent::RedrawRequested if app_state.render() => {
+                app_state.get_window().request_redraw();
             }
             WindowEvent::Resized(size) => {
                 if app_state.resize(size) {
@@ -243,58 +353,255 @@ impl ApplicationHandler for App {
             WindowEvent::CursorMoved { position, .. } => {
                 app_state.mouse_position = position;
             }
-            WindowEvent::MouseInput { state, button, .. } => match button {
-                winit::event::MouseButton::Left => match state {
-                    winit::event::ElementState::Pressed => {
-                        app_state.action = Some(Action::Drag(app_state.mouse_position));
-                    }
-                    winit::event::ElementState::Released => {
-                        app_state.action = None;
-                    }
-                },
-                _ => {}
+            WindowEvent::MouseInput {
+                state,
+                button: winit::event::MouseButton::Left,
+                ..
+            } => match state {
+                winit::event::ElementState::Pressed => {
+                    app_state.action = Some(Action::Drag(app_state.mouse_position));
+                }
+                winit::event::ElementState::Released => {
+                    app_state.action = None;
+                }
             },
-            WindowEvent::MouseWheel { delta, .. } => match delta {
-                winit::event::MouseScrollDelta::LineDelta(_, dy) => {
-                    const SCROLL_SPEED: f32 = 1.0 / 5.0;
+            WindowEvent::MouseInput {
+                state: winit::event::ElementState::Pressed,
+                button: winit::event::MouseButton::Right,
+                ..
+            } => {
+                app_state.pick_path_tile();
+            }
+            WindowEvent::MouseWheel {
+                delta: winit::event::MouseScrollDelta::LineDelta(_, dy),
+                ..
+            } => {
+                const SCROLL_SPEED: f32 = 1.0 / 5.0;
 
-                    let mut scale = app_state.camera.scale;
-                    let mut old_scale = scale;
+                let mut scale = app_state.camera.scale;
+                let mut old_scale = scale;
 
-                    if old_scale == 0.0f32 {
-                        old_scale = 0.01f32;
-                    }
+                if old_scale == 0.0f32 {
+                    old_scale = 0.01f32;
+                }
 
-                    scale = scale - (scale * (dy * SCROLL_SPEED));
+                scale = scale - (scale * (dy * SCROLL_SPEED));
 
-                    // Calculate world difference change and reposition the camera so we keep pointing at the same world tile in the new scale.
-                    let old_world_pos = app_state.camera.unproject(glam::Vec2::new(
-                        app_state.mouse_position.x as f32,
-                        app_state.mouse_position.y as f32,
-                    ));
-                    let world_offset =
-                        (old_world_pos - app_state.camera.position) * (1.0f32 / old_scale);
+                // Calculate world difference change and reposition the camera so we keep pointing at the same world tile in the new scale.
+                let old_world_pos = app_state.camera.unproject(glam::Vec2::new(
+                    app_state.mouse_position.x as f32,
+                    app_state.mouse_position.y as f32,
+                ));
+                let world_offset =
+                    (old_world_pos - app_state.camera.position) * (1.0f32 / old_scale);
 
-                    app_state.camera.position += world_offset * (old_scale - scale);
-                    app_state.camera.set_scale(scale);
-                }
-                _ => {}
-            },
+                app_state.camera.position += world_offset * (old_scale - scale);
+                app_state.camera.set_scale(scale);
+            }
             _ => (),
         }
     }
 }
 
+// Shows how many problems were found when loading the map so they don't go unnoticed.
+fn window_title(map: &Map) -> String {
+    let filename = map.filename.as_deref().unwrap_or("untitled");
+
+    match map.diagnostics.len() {
+        0 => format!("plume - {}", filename),
+        1 => format!("plume - {} (1 problem)", filename),
+        count => format!("plume - {} ({} problems)", filename, count),
+    }
+}
+
+// Parses a tile position written as x,y.
+fn parse_position(text: &str) -> anyhow::Result<(u16, u16)> {
+    let (x, y) = text
+        .split_once(',')
+        .ok_or_else(|| anyhow!("expected a position like 512,512 but got {}", text))?;
+    let position = (x.trim().parse()?, y.trim().parse()?);
+
+    if position.0 >= 1024 || position.1 >= 1024 {
+        bail!("position {} is outside of the map", text);
+    }
+
+    Ok(position)
+}
+
+// Usage: plume diff old.lvl new.lvl [--image diff.png] [--patch changes.plmp]
+// Prints what changed between the maps. The image shows the changed tiles on top of the old map and the patch file
+// can be applied to another copy of the old map with plume patch.
+fn run_diff(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let mut filenames = vec![];
+    let mut image = None;
+    let mut patch = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--image" => {
+                image = Some(args.next().ok_or_else(|| anyhow!("--image needs a file"))?);
+            }
+            "--patch" => {
+                patch = Some(args.next().ok_or_else(|| anyhow!("--patch needs a file"))?);
+            }
+            _ if arg.starts_with("--") => bail!("unknown option {}", arg),
+            _ => filenames.push(arg),
+        }
+    }
+
+    let [old_filename, new_filename] = filenames.as_slice() else {
+        bail!("diff needs an old and a new map");
+    };
+
+    let old = Map::load(old_filename)?;
+    let new = Map::load(new_filename)?;
+    let diff = MapDiff::new(&old, &new);
+
+    if diff.is_empty() {
+        println!("no changes");
+    } else {
+        print!("{}", diff);
+    }
+
+    if let Some(image) = image {
+        diff.to_image(&old).save(&image)?;
+    }
+
+    if let Some(patch) = patch {
+        fs::write(&patch, diff.to_bytes())?;
+    }
+
+    Ok(())
+}
+
+// Usage: plume patch map.lvl changes.plmp [--output patched.lvl]
+// Applies a patch from plume diff and saves the map over itself or to the output file. Nothing is saved if the map
+// has changes that conflict with the patch.
+fn run_patch(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let mut filenames = vec![];
+    let mut output = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => {
+                output = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("--output needs a file"))?,
+                );
+            }
+            _ if arg.starts_with("--") => bail!("unknown option {}", arg),
+            _ => filenames.push(arg),
+        }
+    }
+
+    let [map_filename, patch_filename] = filenames.as_slice() else {
+        bail!("patch needs a map and a patch file");
+    };
+
+    let mut map = Map::load(map_filename)?;
+    let diff = MapDiff::from_bytes(&fs::read(patch_filename)?)?;
+
+    diff.apply(&mut map)?;
+    map.save(
+        output.as_deref().unwrap_or(map_filename),
+        &SaveOptions::default(),
+    )?;
+
+    print!("{}", diff);
+
+    Ok(())
+}
+
+// Usage: plume [map.lvl] [--spawn x,y]... [--settings arena.conf] [--radius pixels]
+// Spawns from the options and the [Spawn] section of the settings file are used to highlight open space that
+// can't be reached. The radius is the ship size for both that and paths picked with the right mouse button.
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    // Warnings are shown by default since that's how the map diagnostics and reachability reports are printed.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    match std::env::args().nth(1).as_deref() {
+        Some("diff") => return run_diff(std::env::args().skip(2)),
+        Some("patch") => return run_patch(std::env::args().skip(2)),
+        _ => {}
+    }
+
+    let mut filename = "test.lvl".to_owned();
+    let mut spawns = vec![];
+    let mut radius = DEFAULT_SHIP_RADIUS;
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--spawn" => {
+                let position = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--spawn needs a position"))?;
+                spawns.push(parse_position(&position)?);
+            }
+            "--settings" => {
+                let settings = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--settings needs a file"))?;
+                spawns.extend(reachability::load_spawns(&settings)?);
+            }
+            "--radius" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--radius needs a value"))?;
+                radius = value.parse()?;
+            }
+            _ if arg.starts_with("--") => bail!("unknown option {}", arg),
+            _ => filename = arg,
+        }
+    }
+
+    let map = map::Map::load(&filename)?;
+
+    for diagnostic in map.diagnostics.iter() {
+        log::warn!("{}: {}", filename, diagnostic);
+    }
+
+    let mut title = window_title(&map);
+    let mut overlay = image::RgbaImage::new(1024, 1024);
+
+    if !spawns.is_empty() {
+        let grid = map.walkability(radius);
+        let reachability = Reachability::new(&grid, &spawns);
+
+        for spawn in &reachability.blocked_spawns {
+            log::warn!(
+                "a ship with radius {} doesn't fit at spawn {:?}",
+                radius,
+                spawn
+            );
+        }
+
+        for pocket in &reachability.pockets {
+            let bounds = pocket.bounds;
+            log::warn!(
+                "unreachable area of {} tiles from ({}, {}) to ({}, {})",
+                pocket.area,
+                bounds.left,
+                bounds.top,
+                bounds.right - 1,
+                bounds.bottom - 1
+            );
+        }
+
+        title = format!(
+            "{} - {} unreachable tiles in {} areas",
+            title,
+            reachability.unreachable_area(),
+            reachability.pockets.len()
+        );
+        overlay = reachability.overlay(&grid);
+    }
 
     let event_loop = EventLoop::new().unwrap();
 
     event_loop.set_control_flow(ControlFlow::Poll);
 
-    let map = map::Map::load("test.lvl")?;
-
-    let mut app = App::new(map);
+    let mut app = App::new(map, title, overlay, radius);
 
     event_loop.run_app(&mut app).unwrap();
 
@@ -1,7 +1,16 @@
-use crate::elvl;
+use crate::{
+    collision::{self, TileKind},
+    elvl, tileset,
+    walkability::WalkabilityGrid,
+};
 use anyhow::*;
 use image::{self, RgbaImage};
-use std::{fs, io::Cursor};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs,
+    io::{Cursor, Read},
+};
 
 pub type TileId = u8;
 
@@ -10,8 +19,24 @@ pub const TILE_ID_LAST_DOOR: TileId = 169;
 pub const TILE_ID_FLAG: TileId = 170;
 pub const TILE_ID_SAFE: TileId = 171;
 pub const TILE_ID_GOAL: TileId = 172;
+pub const TILE_ID_INVISIBLE_WALL: TileId = 191;
+pub const TILE_ID_LARGE_ASTEROID: TileId = 216;
+pub const TILE_ID_SMALL_ASTEROID_1: TileId = 217;
+pub const TILE_ID_SMALL_ASTEROID_2: TileId = 218;
+pub const TILE_ID_SPACE_STATION: TileId = 219;
 pub const TILE_ID_WORMHOLE: TileId = 220;
 
+pub const ATTRIBUTE_NAME: &str = "NAME";
+pub const ATTRIBUTE_VERSION: &str = "VERSION";
+pub const ATTRIBUTE_ZONE: &str = "ZONE";
+pub const ATTRIBUTE_MAP_CREATOR: &str = "MAPCREATOR";
+pub const ATTRIBUTE_TILESET_CREATOR: &str = "TILESETCREATOR";
+pub const ATTRIBUTE_PROGRAM: &str = "PROGRAM";
+
+pub const PROGRAM_NAME: &str = concat!("plume ", env!("CARGO_PKG_VERSION"));
+
+const BITMAP_FILE_HEADER_SIZE: usize = 14;
+
 struct ReadTile {
     value: u32,
 }
@@ -34,33 +59,351 @@ impl ReadTile {
     }
 }
 
+fn write_tile(x: u16, y: u16, id: TileId) -> u32 {
+    (x as u32 & 0xFFF) | ((y as u32 & 0xFFF) << 12) | ((id as u32) << 24)
+}
+
+pub struct SaveOptions {
+    // Writes the tileset bitmap before the tile data when the map has one.
+    pub include_tileset: bool,
+    // Sets the PROGRAM attribute to plume before writing.
+    pub stamp_program: bool,
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        Self {
+            include_tileset: true,
+            stamp_program: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    // A metadata chunk failed to parse. It is kept unparsed so saving writes it back unchanged.
+    Metadata(elvl::ElvlError),
+    // The tileset couldn't be used. A metadata tileset falls back to the one in the file body and a file body tileset
+    // falls back to the placeholder.
+    InvalidTileset(String),
+    // The tile coordinates are outside of the map. The tile is skipped.
+    TileOutOfBounds { x: u16, y: u16, id: TileId },
+    // The tile was already set by an earlier entry. The later entry wins.
+    DuplicateTile { x: u16, y: u16, id: TileId },
+    // The client doesn't do anything with the tile id, so the tile won't show up in game.
+    IgnoredTileId { x: u16, y: u16, id: TileId },
+    // The tile data ends with bytes that don't make up a full tile. They are skipped.
+    TrailingBytes(usize),
+}
+
+impl std::fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiagnosticKind::Metadata(e) => write!(f, "{} in {} chunk", e.kind, e.chunk_name()),
+            DiagnosticKind::InvalidTileset(e) => write!(f, "invalid tileset: {}", e),
+            DiagnosticKind::TileOutOfBounds { x, y, id } => {
+                write!(f, "tile {} at ({}, {}) is outside of the map", id, x, y)
+            }
+            DiagnosticKind::DuplicateTile { x, y, id } => {
+                write!(f, "tile {} at ({}, {}) replaces an earlier tile", id, x, y)
+            }
+            DiagnosticKind::IgnoredTileId { x, y, id } => {
+                write!(f, "tile {} at ({}, {}) is ignored by the client", id, x, y)
+            }
+            DiagnosticKind::TrailingBytes(count) => {
+                write!(f, "{} trailing bytes after the tile data", count)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    // Byte offset in the file data where the problem is.
+    pub offset: usize,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {}", self.kind, self.offset)
+    }
+}
+
+// Problems found while loading a map, in the order they appear in the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MapDiagnostics {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl MapDiagnostics {
+    pub fn push(&mut self, kind: DiagnosticKind, offset: usize) {
+        self.diagnostics.push(Diagnostic { kind, offset });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.diagnostics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+// Returns true if the client does nothing with tiles of this id.
+fn is_ignored_tile_id(id: TileId) -> bool {
+    matches!(TileKind::from_id(id), TileKind::Empty | TileKind::Unused)
+}
+
+// Objects are at most this many tiles wide and tall.
+pub const MAX_OBJECT_SIZE: u16 = 6;
+
+// Returns the width and height in tiles of the object that the tile id places, or None if it's a normal tile.
+pub fn object_size(id: TileId) -> Option<u16> {
+    match id {
+        TILE_ID_LARGE_ASTEROID => Some(2),
+        TILE_ID_SMALL_ASTEROID_1 | TILE_ID_SMALL_ASTEROID_2 => Some(1),
+        TILE_ID_SPACE_STATION => Some(6),
+        TILE_ID_WORMHOLE => Some(5),
+        _ => None,
+    }
+}
+
+// An object that covers more space than its tile. The tile is the top left corner of the object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapObject {
+    pub id: TileId,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl MapObject {
+    pub fn size(&self) -> u16 {
+        object_size(self.id).unwrap_or(1)
+    }
+
+    // The tiles the object covers. Objects near the edge are cut off by the map bounds.
+    pub fn footprint(&self) -> elvl::TileRect {
+        elvl::TileRect {
+            left: self.x,
+            top: self.y,
+            right: (self.x + self.size()).min(1024),
+            bottom: (self.y + self.size()).min(1024),
+        }
+    }
+}
+
+// A tile inside of an object's footprint that is also used by another tile or object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectOverlap {
+    pub object: MapObject,
+    pub x: u16,
+    pub y: u16,
+    // The tile at the position, or the id of the earlier object that covers it.
+    pub other_id: TileId,
+}
+
+// The tile id that occupies each tile once objects are expanded to their full size.
+pub struct OccupancyGrid {
+    tiles: Box<[TileId; 1024 * 1024]>,
+}
+
+impl OccupancyGrid {
+    pub fn get(&self, x: u16, y: u16) -> TileId {
+        if x >= 1024 || y >= 1024 {
+            return 0;
+        }
+
+        self.tiles[y as usize * 1024 + x as usize]
+    }
+
+    pub fn is_occupied(&self, x: u16, y: u16) -> bool {
+        self.get(x, y) != 0
+    }
+}
+
+// The regions that cover a tile along with their combined flags.
+struct RegionSet {
+    regions: Vec<usize>,
+    flags: u32,
+}
+
+// Lookup of which regions cover each tile of a map. Every distinct combination of regions is stored once and each
+// tile refers to its combination, so a lookup is a single index. Regions are numbered in the order of their chunks,
+// counting only region chunks, so other chunks can be added or removed without making the index stale.
+pub struct RegionIndex {
+    // Set index for each tile. This is empty when there are no region tiles, so maps without regions cost nothing.
+    tile_sets: Vec<u32>,
+    sets: Vec<RegionSet>,
+    names: HashMap<Vec<u8>, usize>,
+}
+
+impl RegionIndex {
+    pub fn new(chunks: &[elvl::Chunk]) -> Self {
+        let mut index = Self {
+            tile_sets: vec![],
+            sets: vec![RegionSet {
+                regions: vec![],
+                flags: 0,
+            }],
+            names: HashMap::new(),
+        };
+
+        // Adding a region to a tile moves it from one set to another. The move is the same for every tile that was in
+        // the same set, so it only needs to be found once.
+        let mut transitions: HashMap<(u32, usize), u32> = HashMap::new();
+
+        let regions = chunks.iter().filter_map(|chunk| match chunk {
+            elvl::Chunk::Region(region) => Some(region),
+            _ => None,
+        });
+
+        for (region_number, region) in regions.enumerate() {
+            // When regions share a name, the first one is found by name.
+            index
+                .names
+                .entry(region.name.clone())
+                .or_insert(region_number);
+
+            if region.tiles.is_empty() {
+                continue;
+            }
+
+            if index.tile_sets.is_empty() {
+                index.tile_sets = vec![0; 1024 * 1024];
+            }
+
+            // Returns the set with the region added to the set.
+            let mut add_region = |set: u32| {
+                *transitions.entry((set, region_number)).or_insert_with(|| {
+                    let mut regions = index.sets[set as usize].regions.clone();
+                    regions.push(region_number);
+
+                    index.sets.push(RegionSet {
+                        regions,
+                        flags: index.sets[set as usize].flags | region.flags,
+                    });
+
+                    (index.sets.len() - 1) as u32
+                })
+            };
+
+            for (y, span) in region.tiles.spans() {
+                let row = y as usize * 1024;
+
+                // Neighboring tiles are usually in the same set, so the move is only looked up when the set changes
+                // along the span.
+                let mut last_move: Option<(u32, u32)> = None;
+
+                for set in &mut index.tile_sets[row + span.start as usize..row + span.end as usize]
+                {
+                    let next = match last_move {
+                        Some((from, to)) if from == *set => to,
+                        _ => add_region(*set),
+                    };
+
+                    last_move = Some((*set, next));
+                    *set = next;
+                }
+            }
+        }
+
+        index
+    }
+
+    // Returns the numbers of the regions that cover the tile, in chunk order.
+    pub fn regions_at(&self, x: u16, y: u16) -> &[usize] {
+        &self.sets[self.set_at(x, y)].regions
+    }
+
+    // Returns the combined RegionFlags of every region that covers the tile.
+    pub fn flags_at(&self, x: u16, y: u16) -> u32 {
+        self.sets[self.set_at(x, y)].flags
+    }
+
+    // Returns the number of the region with the name.
+    pub fn find(&self, name: &[u8]) -> Option<usize> {
+        self.names.get(name).copied()
+    }
+
+    fn set_at(&self, x: u16, y: u16) -> usize {
+        if x >= 1024 || y >= 1024 {
+            return 0;
+        }
+
+        self.tile_sets
+            .get(y as usize * 1024 + x as usize)
+            .map_or(0, |set| *set as usize)
+    }
+}
+
 pub struct Map {
-    pub filename: String,
+    // The file the map was loaded from. Maps loaded from memory don't have one.
+    pub filename: Option<String>,
     pub elvl: Vec<elvl::Chunk>,
     pub tiles: Box<[TileId; 1024 * 1024]>,
     pub tileset: Option<RgbaImage>,
+    // The tileset bitmap file as it was loaded, so saving writes it back unchanged. Use set_tileset to change the
+    // tileset so this stays in sync.
+    tileset_bitmap: Option<Vec<u8>>,
+    // The bitmap file header of a map that has metadata but no tileset, so saving writes it back unchanged.
+    bitmap_header: Option<Vec<u8>>,
+    // The tile data as it was loaded. Saving writes it back unchanged while the tiles still match it, which keeps the
+    // original tile order.
+    tiledata: Option<Vec<u8>>,
+    // Tile data in the file body that a TILE chunk took priority over. It isn't used, but saving writes it back.
+    body_tiledata: Option<Vec<u8>>,
+    // The file had a metadata block, so saving writes one back even when there are no chunks.
+    had_metadata: bool,
+    // Built when the map is loaded. It needs to be rebuilt with rebuild_region_index after adding, removing or
+    // changing regions.
+    pub region_index: RegionIndex,
+    // Problems found when the map was loaded. This isn't updated when the map changes.
+    pub diagnostics: MapDiagnostics,
 }
 
 impl Map {
     pub fn empty() -> Self {
         Self {
-            filename: String::new(),
+            filename: None,
             elvl: vec![],
             tiles: vec![0; 1024 * 1024].into_boxed_slice().try_into().unwrap(),
             tileset: None,
+            tileset_bitmap: None,
+            bitmap_header: None,
+            tiledata: None,
+            body_tiledata: None,
+            had_metadata: false,
+            region_index: RegionIndex::new(&[]),
+            diagnostics: MapDiagnostics::default(),
         }
     }
 
     pub fn load(filename: &str) -> anyhow::Result<Self> {
-        let mut map = Self {
-            filename: filename.to_owned(),
-            elvl: vec![],
-            tiles: vec![0; 1024 * 1024].into_boxed_slice().try_into().unwrap(),
-            tileset: None,
-        };
-
         let data = fs::read(filename)?;
 
+        Self::parse(&data, Some(filename.to_owned()))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        Self::parse(data, None)
+    }
+
+    pub fn from_reader(mut reader: impl Read) -> anyhow::Result<Self> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+
+        Self::parse(&data, None)
+    }
+
+    fn parse(data: &[u8], filename: Option<String>) -> anyhow::Result<Self> {
+        let mut map = Self::empty();
+        map.filename = filename;
+
         // Fully empty map is fine.
         if data.len() < 2 {
             return Ok(map);
@@ -73,36 +416,420 @@ impl Map {
                 return Err(anyhow!("invalid bitmap header"));
             }
 
-            let img = image::ImageReader::new(Cursor::new(data.clone()))
-                .with_guessed_format()?
-                .decode()?;
+            tiledata_offset = u32::from_le_bytes(data[2..6].try_into().unwrap()) as usize;
 
-            map.tileset = Some(img.into_rgba8());
+            if tiledata_offset > data.len() {
+                return Err(anyhow!("tile data offset larger than file data length"));
+            }
 
-            tiledata_offset = u32::from_le_bytes(data[2..6].try_into().unwrap()) as usize;
+            // When there's metadata, the bitmap ends where the metadata starts.
+            let metadata_offset = u32::from_le_bytes(data[6..10].try_into().unwrap()) as usize;
+            let bitmap_end = if metadata_offset != 0 && metadata_offset < tiledata_offset {
+                metadata_offset
+            } else {
+                tiledata_offset
+            };
+
+            // Maps with metadata but no tileset only have the bitmap file header.
+            if bitmap_end > BITMAP_FILE_HEADER_SIZE {
+                let bitmap = &data[..bitmap_end];
+
+                // The rest of the map can still be used without the tileset. The bitmap is kept either way so saving
+                // writes it back unchanged.
+                match tileset::decode_tileset(bitmap) {
+                    Result::Ok(tileset) => map.tileset = Some(tileset),
+                    Err(e) => map
+                        .diagnostics
+                        .push(DiagnosticKind::InvalidTileset(format!("{:#}", e)), 0),
+                }
+
+                map.tileset_bitmap = Some(bitmap.to_owned());
+            } else if bitmap_end == BITMAP_FILE_HEADER_SIZE {
+                map.bitmap_header = Some(data[..BITMAP_FILE_HEADER_SIZE].to_owned());
+            }
+        }
+
+        map.had_metadata = elvl::has_metadata(data);
+
+        let (elvl, warnings) = elvl::elvl_read_with_offsets(data);
+
+        for warning in warnings {
+            let offset = warning.offset;
+            map.diagnostics
+                .push(DiagnosticKind::Metadata(warning), offset);
+        }
+
+        let (offsets, chunks): (Vec<usize>, Vec<elvl::Chunk>) = elvl.into_iter().unzip();
+        map.elvl = chunks;
+
+        // Tileset and tile data embedded in the metadata take priority over the ones in the file body.
+        let body_tiledata = &data[tiledata_offset..];
+        let mut tiledata = body_tiledata;
+
+        for (offset, chunk) in offsets.iter().zip(&map.elvl) {
+            match chunk {
+                elvl::Chunk::Tileset(bitmap) => match tileset::decode_tileset(bitmap) {
+                    Result::Ok(tileset) => map.tileset = Some(tileset),
+                    Err(e) => map
+                        .diagnostics
+                        .push(DiagnosticKind::InvalidTileset(format!("{:#}", e)), *offset),
+                },
+                elvl::Chunk::Tile(chunk_tiledata) => {
+                    tiledata = chunk_tiledata;
+                    tiledata_offset = *offset;
+                }
+                _ => {}
+            }
+        }
+
+        Self::read_tiles(
+            &mut map.tiles,
+            tiledata,
+            tiledata_offset,
+            &mut map.diagnostics,
+        );
+
+        if !tiledata.is_empty() {
+            map.tiledata = Some(tiledata.to_owned());
+        }
+
+        if map.has_tile_chunk() && !body_tiledata.is_empty() {
+            map.body_tiledata = Some(body_tiledata.to_owned());
+        }
+
+        map.rebuild_region_index();
+
+        Ok(map)
+    }
+
+    pub fn save(&mut self, filename: &str, options: &SaveOptions) -> anyhow::Result<()> {
+        if options.stamp_program {
+            self.attributes_mut().stamp_program();
+        }
+
+        fs::write(filename, self.to_bytes(options.include_tileset))?;
+
+        Ok(())
+    }
+
+    // Writes the map in the same layout that load reads. The metadata goes between the tileset bitmap and the tile
+    // data, with a bitmap file header pointing at both. Anything that wasn't changed since loading is written back
+    // as it was read, so saving a loaded map gives the same bytes. Changed tiles are written in row order.
+    pub fn to_bytes(&self, include_tileset: bool) -> Vec<u8> {
+        let tiledata = match &self.tiledata {
+            Some(tiledata) if self.tiles_match(tiledata) => tiledata.clone(),
+            _ => self.write_tiles(),
+        };
+
+        let (metadata, tiledata) = if self.has_tile_chunk() {
+            // Tile data that came from the metadata is written back there so it still takes priority when loading.
+            // The file body keeps whatever tile data it had, even though loading doesn't use it.
+            let tile_chunk = elvl::Chunk::Tile(tiledata);
+
+            let metadata = elvl::elvl_write(self.elvl.iter().map(|chunk| match chunk {
+                elvl::Chunk::Tile(_) => &tile_chunk,
+                _ => chunk,
+            }));
+
+            (metadata, self.body_tiledata.clone().unwrap_or_default())
+        } else if self.elvl.is_empty() && !self.had_metadata {
+            (vec![], tiledata)
+        } else {
+            (elvl::elvl_write(&self.elvl), tiledata)
+        };
+
+        let mut bitmap = match &self.tileset_bitmap {
+            Some(bitmap) if include_tileset => bitmap.clone(),
+            _ if metadata.is_empty() => vec![],
+            _ if let Some(header) = &self.bitmap_header => header.clone(),
+            _ => {
+                let mut header = vec![0; BITMAP_FILE_HEADER_SIZE];
+                header[0] = b'B';
+                header[1] = b'M';
+                header
+            }
+        };
+
+        if !bitmap.is_empty() {
+            let metadata_offset = if metadata.is_empty() { 0 } else { bitmap.len() };
+            let tiledata_offset = bitmap.len() + metadata.len();
+
+            bitmap[2..6].copy_from_slice(&(tiledata_offset as u32).to_le_bytes());
+            bitmap[6..10].copy_from_slice(&(metadata_offset as u32).to_le_bytes());
+        }
+
+        let mut data = bitmap;
+        data.extend_from_slice(&metadata);
+        data.extend_from_slice(&tiledata);
+
+        data
+    }
+
+    fn has_tile_chunk(&self) -> bool {
+        self.elvl
+            .iter()
+            .any(|chunk| matches!(chunk, elvl::Chunk::Tile(_)))
+    }
+
+    fn write_tiles(&self) -> Vec<u8> {
+        let mut tiledata = vec![];
+
+        for y in 0..1024 {
+            for x in 0..1024 {
+                let id = self.tiles[y as usize * 1024 + x as usize];
+
+                if id != 0 {
+                    tiledata.extend_from_slice(&write_tile(x, y, id).to_le_bytes());
+                }
+            }
         }
 
-        if tiledata_offset >= data.len() {
-            return Err(anyhow!("tile data offset larger than file data length"));
+        tiledata
+    }
+
+    // Returns true if reading the tile data gives the current tiles.
+    fn tiles_match(&self, tiledata: &[u8]) -> bool {
+        let mut tiles: Box<[TileId; 1024 * 1024]> =
+            vec![0; 1024 * 1024].into_boxed_slice().try_into().unwrap();
+
+        Self::read_tiles(&mut tiles, tiledata, 0, &mut MapDiagnostics::default());
+
+        tiles == self.tiles
+    }
+
+    // Replaces the tileset. The bitmap is stored wherever the current tileset came from, so the metadata tileset is
+    // replaced if there is one.
+    pub fn set_tileset(&mut self, tileset: RgbaImage) -> anyhow::Result<()> {
+        let tileset = tileset::normalize_tileset(tileset)?;
+        let mut bitmap = Cursor::new(vec![]);
+
+        image::DynamicImage::ImageRgba8(tileset.clone())
+            .to_rgb8()
+            .write_to(&mut bitmap, image::ImageFormat::Bmp)?;
+
+        self.set_tileset_bitmap(bitmap.into_inner(), tileset);
+
+        Ok(())
+    }
+
+    // Removes the tileset from the map, including any metadata tileset, so the client uses its own default tileset.
+    pub fn use_default_tileset(&mut self) {
+        self.elvl
+            .retain(|chunk| !matches!(chunk, elvl::Chunk::Tileset(_)));
+        self.tileset_bitmap = None;
+        self.tileset = None;
+    }
+
+    // Returns the tileset of the map or the placeholder tileset if it doesn't have one. This is only for showing the
+    // map, since the placeholder isn't what players see.
+    pub fn tileset_or_placeholder(&self) -> &RgbaImage {
+        self.tileset
+            .as_ref()
+            .unwrap_or_else(|| tileset::placeholder_tileset())
+    }
+
+    fn set_tileset_bitmap(&mut self, bitmap: Vec<u8>, tileset: RgbaImage) {
+        match self.elvl.iter_mut().find_map(|chunk| match chunk {
+            elvl::Chunk::Tileset(chunk_bitmap) => Some(chunk_bitmap),
+            _ => None,
+        }) {
+            Some(chunk_bitmap) => *chunk_bitmap = bitmap,
+            None => self.tileset_bitmap = Some(bitmap),
         }
 
-        let tiledata = &data[tiledata_offset..];
-        let tiledata_size = data.len() - tiledata_offset;
-        let tile_count = tiledata_size / size_of::<u32>();
+        self.tileset = Some(tileset);
+    }
+
+    pub fn rebuild_region_index(&mut self) {
+        self.region_index = RegionIndex::new(&self.elvl);
+    }
+
+    // Returns every region chunk in order. The position of a region here is its number in the region index.
+    pub fn regions(&self) -> impl Iterator<Item = &elvl::Region> {
+        self.elvl.iter().filter_map(|chunk| match chunk {
+            elvl::Chunk::Region(region) => Some(region),
+            _ => None,
+        })
+    }
+
+    pub fn regions_at(&self, x: u16, y: u16) -> impl Iterator<Item = &elvl::Region> {
+        let numbers = self.region_index.regions_at(x, y);
+
+        // The numbers are sorted, so the regions can be matched up in a single pass.
+        self.regions()
+            .enumerate()
+            .filter(move |(number, _)| numbers.binary_search(number).is_ok())
+            .map(|(_, region)| region)
+    }
+
+    // Returns the combined RegionFlags of every region that covers the tile.
+    pub fn region_flags_at(&self, x: u16, y: u16) -> u32 {
+        self.region_index.flags_at(x, y)
+    }
+
+    pub fn region(&self, name: impl AsRef<[u8]>) -> Option<&elvl::Region> {
+        self.regions().nth(self.region_index.find(name.as_ref())?)
+    }
+
+    // Returns every object in the map in row order.
+    pub fn objects(&self) -> Vec<MapObject> {
+        self.tiles
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| object_size(**id).is_some())
+            .map(|(index, id)| MapObject {
+                id: *id,
+                x: (index % 1024) as u16,
+                y: (index / 1024) as u16,
+            })
+            .collect()
+    }
+
+    // Finds the tiles inside of object footprints that are also used by other tiles or objects.
+    pub fn object_overlaps(&self) -> Vec<ObjectOverlap> {
+        let mut overlaps = vec![];
+        // Id of the object covering each tile, filled in as objects are visited.
+        let mut covered = vec![0 as TileId; 1024 * 1024];
+
+        for object in self.objects() {
+            let footprint = object.footprint();
+
+            for y in footprint.top..footprint.bottom {
+                for x in footprint.left..footprint.right {
+                    let index = y as usize * 1024 + x as usize;
+
+                    // Anything covering the object's own tile is an earlier object that already reported it.
+                    let other_id = if self.tiles[index] != 0 {
+                        self.tiles[index]
+                    } else {
+                        covered[index]
+                    };
+
+                    if (x, y) != (object.x, object.y) && other_id != 0 {
+                        overlaps.push(ObjectOverlap {
+                            object,
+                            x,
+                            y,
+                            other_id,
+                        });
+                    }
+
+                    covered[index] = object.id;
+                }
+            }
+        }
+
+        overlaps
+    }
+
+    // Returns the id of the tile or object that occupies the tile, the same as occupancy_grid would.
+    pub fn occupant(&self, x: u16, y: u16) -> TileId {
+        if x >= 1024 || y >= 1024 {
+            return 0;
+        }
+
+        // Only objects up and to the left can reach this tile. They are checked in reverse row order so the object
+        // on top is found first.
+        for object_y in (y.saturating_sub(MAX_OBJECT_SIZE - 1)..=y).rev() {
+            for object_x in (x.saturating_sub(MAX_OBJECT_SIZE - 1)..=x).rev() {
+                let object = MapObject {
+                    id: self.tiles[object_y as usize * 1024 + object_x as usize],
+                    x: object_x,
+                    y: object_y,
+                };
+
+                if object_size(object.id).is_some() && object.footprint().contains(x, y) {
+                    return object.id;
+                }
+            }
+        }
+
+        self.tiles[y as usize * 1024 + x as usize]
+    }
+
+    // Returns true if ships collide with the tile, including tiles covered by objects. Everything outside of the
+    // map is solid.
+    pub fn is_solid(&self, x: u16, y: u16) -> bool {
+        if x >= 1024 || y >= 1024 {
+            return true;
+        }
+
+        collision::is_solid(self.occupant(x, y))
+    }
+
+    // Computes where a ship with the radius in pixels fits. The grid can be kept up to date with
+    // WalkabilityGrid::update as tiles change.
+    pub fn walkability(&self, radius: u16) -> WalkabilityGrid {
+        WalkabilityGrid::new(self, radius)
+    }
+
+    // Builds the grid of occupied tiles where objects fill their whole footprint. Objects are placed over normal
+    // tiles, and later objects in row order are placed over earlier ones.
+    pub fn occupancy_grid(&self) -> OccupancyGrid {
+        let mut grid = OccupancyGrid {
+            tiles: self.tiles.clone(),
+        };
+
+        for object in self.objects() {
+            let footprint = object.footprint();
+
+            for y in footprint.top..footprint.bottom {
+                let start = y as usize * 1024;
+                grid.tiles[start + footprint.left as usize..start + footprint.right as usize]
+                    .fill(object.id);
+            }
+        }
+
+        grid
+    }
+
+    // Reads the tile data that starts at base_offset in the file data and records any problems with it.
+    fn read_tiles(
+        tiles: &mut [TileId; 1024 * 1024],
+        tiledata: &[u8],
+        base_offset: usize,
+        diagnostics: &mut MapDiagnostics,
+    ) {
+        let tile_count = tiledata.len() / size_of::<u32>();
+        // One bit per tile for finding tiles that are set more than once.
+        let mut seen = vec![0u64; 1024 * 1024 / 64];
 
         for i in 0..tile_count {
             let tile_offset = i * size_of::<u32>();
             let tile = ReadTile::new(u32::from_le_bytes(
                 tiledata[tile_offset..tile_offset + 4].try_into().unwrap(),
             ));
+            let (x, y, id) = (tile.x(), tile.y(), tile.id());
+            let offset = base_offset + tile_offset;
+
+            // The coordinates have room for values outside of the map, so those are ignored.
+            if x >= 1024 || y >= 1024 {
+                diagnostics.push(DiagnosticKind::TileOutOfBounds { x, y, id }, offset);
+                continue;
+            }
+
+            let index = y as usize * 1024 + x as usize;
 
-            let index = tile.y() as usize * 1024 + tile.x() as usize;
-            map.tiles[index] = tile.id();
+            if seen[index / 64] & (1 << (index % 64)) != 0 {
+                diagnostics.push(DiagnosticKind::DuplicateTile { x, y, id }, offset);
+            }
+
+            if is_ignored_tile_id(id) {
+                diagnostics.push(DiagnosticKind::IgnoredTileId { x, y, id }, offset);
+            }
+
+            seen[index / 64] |= 1 << (index % 64);
+            tiles[index] = id;
         }
 
-        map.elvl = elvl::elvl_read(&data)?;
+        let trailing = tiledata.len() % size_of::<u32>();
 
-        Ok(map)
+        if trailing != 0 {
+            diagnostics.push(
+                DiagnosticKind::TrailingBytes(trailing),
+                base_offset + tiledata.len() - trailing,
+            );
+        }
     }
 
     pub fn get_attributes(&self) -> Vec<&elvl::Attribute> {
@@ -114,4 +841,447 @@ impl Map {
             })
             .collect()
     }
+
+    pub fn attributes(&self) -> MapAttributes<&Vec<elvl::Chunk>> {
+        MapAttributes { elvl: &self.elvl }
+    }
+
+    pub fn attributes_mut(&mut self) -> MapAttributes<&mut Vec<elvl::Chunk>> {
+        MapAttributes {
+            elvl: &mut self.elvl,
+        }
+    }
+
+    // Writes the eLVL metadata block for the map, optionally setting PROGRAM to plume first.
+    pub fn write_elvl(&mut self, stamp_program: bool) -> Vec<u8> {
+        if stamp_program {
+            self.attributes_mut().stamp_program();
+        }
+
+        elvl::elvl_write(&self.elvl)
+    }
+}
+
+// Typed view over the attribute chunks of a map. The attributes stay in the map's eLVL chunks, so the original
+// order and any unknown keys are kept.
+pub struct MapAttributes<E> {
+    elvl: E,
+}
+
+impl<E: AsRef<[elvl::Chunk]>> MapAttributes<E> {
+    pub fn iter(&self) -> impl Iterator<Item = &elvl::Attribute> {
+        self.elvl.as_ref().iter().filter_map(|chunk| match chunk {
+            elvl::Chunk::Attribute(attr) => Some(attr),
+            _ => None,
+        })
+    }
+
+    // Returns the raw value bytes of the first attribute with the key.
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.iter()
+            .find(|attr| attr.key == key.as_bytes())
+            .map(|attr| attr.value.as_slice())
+    }
+
+    // Returns the value of the first attribute with the key, decoded for display.
+    pub fn get_text(&self, key: &str) -> Option<Cow<'_, str>> {
+        self.get(key).map(elvl::decode_text)
+    }
+
+    pub fn name(&self) -> Option<Cow<'_, str>> {
+        self.get_text(ATTRIBUTE_NAME)
+    }
+
+    pub fn version(&self) -> Option<Cow<'_, str>> {
+        self.get_text(ATTRIBUTE_VERSION)
+    }
+
+    pub fn zone(&self) -> Option<Cow<'_, str>> {
+        self.get_text(ATTRIBUTE_ZONE)
+    }
+
+    pub fn map_creator(&self) -> Option<Cow<'_, str>> {
+        self.get_text(ATTRIBUTE_MAP_CREATOR)
+    }
+
+    pub fn tileset_creator(&self) -> Option<Cow<'_, str>> {
+        self.get_text(ATTRIBUTE_TILESET_CREATOR)
+    }
+
+    pub fn program(&self) -> Option<Cow<'_, str>> {
+        self.get_text(ATTRIBUTE_PROGRAM)
+    }
+}
+
+impl MapAttributes<&mut Vec<elvl::Chunk>> {
+    // Sets the value of an existing attribute in place or adds it after the last attribute.
+    // The value can be any bytes, so text in other encodings can be stored as is.
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        let key = key.as_ref();
+        let value = value.as_ref();
+        let mut last_attribute_index = None;
+
+        for (index, chunk) in self.elvl.iter_mut().enumerate() {
+            if let elvl::Chunk::Attribute(attr) = chunk {
+                if attr.key == key {
+                    attr.value = value.to_owned();
+                    return;
+                }
+
+                last_attribute_index = Some(index);
+            }
+        }
+
+        let insert_index = last_attribute_index.map_or(0, |index| index + 1);
+
+        self.elvl.insert(
+            insert_index,
+            elvl::Chunk::Attribute(elvl::Attribute {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }),
+        );
+    }
+
+    // Removes every attribute with the key.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) {
+        let key = key.as_ref();
+
+        self.elvl
+            .retain(|chunk| !matches!(chunk, elvl::Chunk::Attribute(attr) if attr.key == key));
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.set(ATTRIBUTE_NAME, name);
+    }
+
+    pub fn set_version(&mut self, version: &str) {
+        self.set(ATTRIBUTE_VERSION, version);
+    }
+
+    pub fn set_zone(&mut self, zone: &str) {
+        self.set(ATTRIBUTE_ZONE, zone);
+    }
+
+    pub fn set_map_creator(&mut self, creator: &str) {
+        self.set(ATTRIBUTE_MAP_CREATOR, creator);
+    }
+
+    pub fn set_tileset_creator(&mut self, creator: &str) {
+        self.set(ATTRIBUTE_TILESET_CREATOR, creator);
+    }
+
+    pub fn set_program(&mut self, program: &str) {
+        self.set(ATTRIBUTE_PROGRAM, program);
+    }
+
+    pub fn stamp_program(&mut self) {
+        self.set_program(PROGRAM_NAME);
+    }
+
+    // Increments the last number in VERSION, keeping the rest of the text and the digit count.
+    // "1.9" becomes "1.10", "v07" becomes "v08" and a version without any number gets ".1" appended.
+    // A map without a version starts at "1". The digits are changed in the raw value, so text in other encodings is
+    // kept unchanged. Returns the new version decoded for display.
+    pub fn bump_version(&mut self) -> String {
+        let version = match self.get(ATTRIBUTE_VERSION) {
+            Some(version) => bump_version_bytes(version),
+            None => b"1".to_vec(),
+        };
+
+        self.set(ATTRIBUTE_VERSION, &version);
+
+        elvl::decode_text(&version).into_owned()
+    }
+}
+
+// Works on bytes so versions in any encoding can be bumped. Digits are always single ASCII bytes, and those bytes
+// never show up inside of multi-byte characters.
+fn bump_version_bytes(version: &[u8]) -> Vec<u8> {
+    let digits_end = match version.iter().rposition(|c| c.is_ascii_digit()) {
+        Some(index) => index + 1,
+        None => return [version, b".1"].concat(),
+    };
+
+    let digits_start = version[..digits_end]
+        .iter()
+        .rposition(|c| !c.is_ascii_digit())
+        .map_or(0, |index| index + 1);
+
+    let mut digits = version[digits_start..digits_end].to_vec();
+
+    // Carry through the digits so numbers of any length can be bumped.
+    let mut index = digits.len();
+    loop {
+        if index == 0 {
+            digits.insert(0, b'1');
+            break;
+        }
+
+        index -= 1;
+
+        if digits[index] == b'9' {
+            digits[index] = b'0';
+        } else {
+            digits[index] += 1;
+            break;
+        }
+    }
+
+    [&version[..digits_start], &digits, &version[digits_end..]].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Rng, chunk, map_file, metadata, random_map, tile};
+
+    #[test]
+    fn changed_maps_never_panic() {
+        let mut rng = Rng::new(0xfeed_face_dead_c0de);
+
+        // Row 0 has 10 tiles and the rest of the rows are empty.
+        let region_tiles = [0b010_01001, 0b001_00011, 0xF5, 0b101_00011, 0xFE];
+        let region = [chunk(b"rNAM", b"region"), chunk(b"rTIL", &region_tiles)].concat();
+        let chunks = [chunk(b"ATTR", b"NAME=test"), chunk(b"REGN", &region)].concat();
+
+        let tiledata: Vec<u8> = (0..500)
+            .flat_map(|_| {
+                tile(
+                    rng.below(1024) as u16,
+                    rng.below(1024) as u16,
+                    rng.below(256) as u8,
+                )
+            })
+            .collect();
+
+        let original = map_file(&metadata(&chunks), &tiledata);
+        let path = std::env::temp_dir().join(format!("plume-test-{}.lvl", std::process::id()));
+
+        for _ in 0..200 {
+            let mut data = original.clone();
+            rng.mutate(&mut data);
+            fs::write(&path, &data).unwrap();
+
+            // Only panics are failures. Broken maps either fail to load or load without the broken parts.
+            let _ = Map::load(path.to_str().unwrap());
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn broken_region_is_saved_unchanged() {
+        // The second run goes past the end of row 0.
+        let region = [
+            chunk(b"rNAM", b"region"),
+            chunk(b"rTIL", &[0b000_00000, 0b011_00011, 0xFF]),
+        ]
+        .concat();
+
+        let data = map_file(&metadata(&chunk(b"REGN", &region)), &tile(1, 2, 3));
+        let map = Map::from_bytes(&data).unwrap();
+
+        assert_eq!(map.diagnostics.len(), 1);
+        assert_eq!(map.regions().count(), 0);
+        assert_eq!(map.to_bytes(true), data);
+    }
+
+    fn bumped_version(version: &[u8]) -> (String, Vec<u8>) {
+        let mut map = Map::empty();
+        map.attributes_mut().set(ATTRIBUTE_VERSION, version);
+
+        let text = map.attributes_mut().bump_version();
+
+        (
+            text,
+            map.attributes().get(ATTRIBUTE_VERSION).unwrap().to_owned(),
+        )
+    }
+
+    #[test]
+    fn bump_version_increments_the_last_number() {
+        assert_eq!(
+            bumped_version(b"1.9"),
+            ("1.10".to_owned(), b"1.10".to_vec())
+        );
+        assert_eq!(bumped_version(b"v07"), ("v08".to_owned(), b"v08".to_vec()));
+        assert_eq!(bumped_version(b"99"), ("100".to_owned(), b"100".to_vec()));
+        assert_eq!(
+            bumped_version(b"2 beta"),
+            ("3 beta".to_owned(), b"3 beta".to_vec())
+        );
+    }
+
+    #[test]
+    fn bump_version_without_digits_appends_one() {
+        assert_eq!(
+            bumped_version(b"beta"),
+            ("beta.1".to_owned(), b"beta.1".to_vec())
+        );
+        assert_eq!(bumped_version(b""), (".1".to_owned(), b".1".to_vec()));
+    }
+
+    #[test]
+    fn bump_version_keeps_windows_1252_text() {
+        // 0xE9 is 'é' in Windows-1252 and isn't valid UTF-8.
+        assert_eq!(
+            bumped_version(b"v2 caf\xe9"),
+            ("v3 café".to_owned(), b"v3 caf\xe9".to_vec())
+        );
+        assert_eq!(
+            bumped_version(b"\xe95"),
+            ("é6".to_owned(), b"\xe96".to_vec())
+        );
+    }
+
+    #[test]
+    fn bump_version_without_version_starts_at_one() {
+        let mut map = Map::empty();
+
+        assert_eq!(map.attributes_mut().bump_version(), "1");
+        assert_eq!(map.attributes().get(ATTRIBUTE_VERSION), Some(&b"1"[..]));
+    }
+
+    #[test]
+    fn region_index_matches_the_regions() {
+        let mut rng = Rng::new(0x5851_f42d_4c95_7f2d);
+        let mut chunks = vec![elvl::Chunk::Other(0, vec![])];
+
+        for _ in 0..6 {
+            let mut region = elvl::Region::empty();
+            region.flags = 1 << rng.below(4);
+
+            for _ in 0..rng.below(200) {
+                let start = rng.below(1024) as u16;
+                let end = start + rng.below(1024 - start as u64 + 1) as u16;
+
+                region.set_span(rng.below(64) as u16, start, end);
+            }
+
+            chunks.push(elvl::Chunk::Region(region));
+        }
+
+        let index = RegionIndex::new(&chunks);
+        let regions: Vec<&elvl::Region> = chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                elvl::Chunk::Region(region) => Some(region),
+                _ => None,
+            })
+            .collect();
+
+        for y in 0..65 {
+            for x in 0..1024 {
+                let covering: Vec<usize> = (0..regions.len())
+                    .filter(|number| regions[*number].in_region(x, y))
+                    .collect();
+                let flags = covering
+                    .iter()
+                    .fold(0, |flags, number| flags | regions[*number].flags);
+
+                assert_eq!(index.regions_at(x, y), covering);
+                assert_eq!(index.flags_at(x, y), flags);
+            }
+        }
+    }
+
+    #[test]
+    fn saved_maps_load_the_same() {
+        let mut rng = Rng::new(0x0123_4567_89ab_cdef);
+
+        for _ in 0..20 {
+            let map = random_map(&mut rng);
+            let data = map.to_bytes(true);
+            let loaded = Map::from_bytes(&data).unwrap();
+
+            assert!(loaded.diagnostics.is_empty());
+            assert!(loaded.tiles == map.tiles);
+            assert!(loaded.regions().eq(map.regions()));
+            assert_eq!(loaded.attributes().name().as_deref(), Some("test"));
+            assert_eq!(loaded.to_bytes(true), data);
+        }
+    }
+
+    #[test]
+    fn loaded_map_saves_the_same_bytes() {
+        // Row 0 has two present tiles written as two runs instead of one, then the remaining 1023 rows are empty.
+        let region_tiles = [
+            0b010_00000,
+            0b010_00000,
+            0b001_00011,
+            0xFD,
+            0b101_00011,
+            0xFE,
+        ];
+
+        // The unknown sub-chunk sits between the known ones.
+        let region = [
+            chunk(b"rNAM", b"region"),
+            chunk(b"rTIL", &region_tiles),
+            chunk(b"rXYZ", &[1, 2, 3]),
+            chunk(b"rBSE", &[]),
+        ]
+        .concat();
+
+        let chunks = [chunk(b"ATTR", b"NAME=test"), chunk(b"REGN", &region)].concat();
+
+        // Tiles that aren't in row order.
+        let tiledata = [tile(5, 5, 1), tile(1, 1, 2), tile(3, 0, 3)].concat();
+
+        let data = map_file(&metadata(&chunks), &tiledata);
+        let mut map = Map::from_bytes(&data).unwrap();
+
+        assert!(map.diagnostics.is_empty());
+        assert_eq!(map.tiles[5 * 1024 + 5], 1);
+        assert_eq!(map.region("region").unwrap().tile_count, 2);
+        assert_eq!(map.region_flags_at(1, 0), elvl::RegionFlags::Base);
+        assert_eq!(map.to_bytes(true), data);
+
+        // Changing the tiles writes them in row order instead.
+        map.tiles[0] = 4;
+
+        let changed = Map::from_bytes(&map.to_bytes(true)).unwrap();
+        assert!(changed.tiles == map.tiles);
+        assert_eq!(changed.to_bytes(true), map.to_bytes(true));
+    }
+
+    #[test]
+    fn empty_metadata_is_saved_unchanged() {
+        let data = map_file(&metadata(&[]), &tile(1, 2, 3));
+        let map = Map::from_bytes(&data).unwrap();
+
+        assert!(map.elvl.is_empty());
+        assert_eq!(map.to_bytes(true), data);
+    }
+
+    #[test]
+    fn body_tiles_behind_a_tile_chunk_are_saved_unchanged() {
+        let data = map_file(&metadata(&chunk(b"TILE", &tile(1, 2, 3))), &tile(4, 5, 6));
+        let map = Map::from_bytes(&data).unwrap();
+
+        assert_eq!(map.tiles[2 * 1024 + 1], 3);
+        assert_eq!(map.tiles[5 * 1024 + 4], 0);
+        assert_eq!(map.to_bytes(true), data);
+    }
+
+    #[test]
+    fn wrong_size_tileset_still_loads_the_map() {
+        let mut bitmap = Cursor::new(vec![]);
+        RgbaImage::new(16, 16)
+            .write_to(&mut bitmap, image::ImageFormat::Bmp)
+            .unwrap();
+
+        // The bitmap file header already points at the end of the bitmap, which is where the tile data goes.
+        let data = [bitmap.into_inner(), tile(1, 2, 3).to_vec()].concat();
+        let map = Map::from_bytes(&data).unwrap();
+
+        assert!(matches!(
+            map.diagnostics.iter().next().unwrap().kind,
+            DiagnosticKind::InvalidTileset(_)
+        ));
+        assert!(map.tileset.is_none());
+        assert_eq!(map.tiles[2 * 1024 + 1], 3);
+        assert_eq!(map.to_bytes(true), data);
+    }
 }
@@ -1,8 +1,14 @@
-use crate::{camera::Camera, map::Map};
+use crate::{
+    camera::Camera,
+    collision::TileKind,
+    map::Map,
+    tileset::{self, TILESET_HEIGHT, TILESET_WIDTH},
+};
 
 use bytemuck::{Pod, Zeroable};
 use encase::ShaderType;
 use glam::Mat4;
+use image::RgbaImage;
 use wgpu::util::DeviceExt;
 
 #[derive(Debug, ShaderType)]
@@ -55,6 +61,8 @@ pub struct MapRenderer {
 
     tileset_texture: wgpu::Texture,
     tiledata_texture: wgpu::Texture,
+    tile_visibility_texture: wgpu::Texture,
+    overlay_texture: wgpu::Texture,
 }
 
 impl MapRenderer {
@@ -101,6 +109,38 @@ impl MapRenderer {
         let tiledata_texture_view =
             tiledata_texture.create_view(&wgpu::TextureViewDescriptor::default());
 
+        // One texel per tile id that says whether the tile is drawn from the tileset.
+        let tile_visibility_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 256,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Uint,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let tile_visibility_texture_view =
+            tile_visibility_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // One texel per map tile that's blended over the tile.
+        let overlay_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: tiledata_texture_extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let overlay_texture_view =
+            overlay_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
         let tileset_texture_extent = wgpu::Extent3d {
             width: 16,
             height: 16,
@@ -171,6 +211,26 @@ impl MapRenderer {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Uint,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
             ],
         });
 
@@ -194,6 +254,14 @@ impl MapRenderer {
                     binding: 3,
                     resource: wgpu::BindingResource::TextureView(&tiledata_texture_view),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&tile_visibility_texture_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&overlay_texture_view),
+                },
             ],
         });
 
@@ -245,6 +313,8 @@ impl MapRenderer {
 
             tileset_texture,
             tiledata_texture,
+            tile_visibility_texture,
+            overlay_texture,
         }
     }
 
@@ -252,44 +322,76 @@ impl MapRenderer {
         // We need to write into a new data slot so the rows align by 256 bytes.
         let mut custom_data = [0; 64 * 16 * 4];
 
-        if let Some(tileset) = &map.tileset {
-            let tileset_texels = tileset.as_raw().as_slice();
-
-            for tile_id in 0..190 {
-                let tile_x = (tile_id % 19) * 16;
-                let tile_y = (tile_id / 19) * 16;
+        // Maps without a tileset use the placeholder, so the texture never has uninitialized contents.
+        let mut tileset = map.tileset_or_placeholder();
+
+        if tileset.dimensions() != (TILESET_WIDTH, TILESET_HEIGHT) {
+            log::warn!(
+                "tileset is {}x{} instead of {}x{}, using the placeholder tileset",
+                tileset.width(),
+                tileset.height(),
+                TILESET_WIDTH,
+                TILESET_HEIGHT
+            );
+            tileset = tileset::placeholder_tileset();
+        }
 
-                for y in 0..16 {
-                    let write_index_start: usize = (y * 64 * 4) as usize;
-                    let write_index_end: usize = write_index_start + 16 * 4;
+        let tileset_texels = tileset.as_raw().as_slice();
 
-                    let read_index_start: usize = ((tile_y + y) * 304 * 4 + (tile_x * 4)) as usize;
-                    let read_index_end: usize = read_index_start + (16 * 4) as usize;
+        for tile_id in 0..190 {
+            let tile_x = (tile_id % 19) * 16;
+            let tile_y = (tile_id / 19) * 16;
 
-                    custom_data[write_index_start..write_index_end]
-                        .copy_from_slice(&tileset_texels[read_index_start..read_index_end]);
-                }
+            for y in 0..16 {
+                let write_index_start: usize = (y * 64 * 4) as usize;
+                let write_index_end: usize = write_index_start + 16 * 4;
 
-                let mut texture_info = self.tileset_texture.as_image_copy();
-                texture_info.origin.z = tile_id;
+                let read_index_start: usize =
+                    ((tile_y + y) * TILESET_WIDTH * 4 + (tile_x * 4)) as usize;
+                let read_index_end: usize = read_index_start + (16 * 4) as usize;
 
-                queue.write_texture(
-                    texture_info,
-                    &custom_data,
-                    wgpu::TexelCopyBufferLayout {
-                        offset: 0,
-                        bytes_per_row: Some(256),
-                        rows_per_image: Some(16),
-                    },
-                    wgpu::Extent3d {
-                        width: 16,
-                        height: 16,
-                        depth_or_array_layers: 1,
-                    },
-                );
+                custom_data[write_index_start..write_index_end]
+                    .copy_from_slice(&tileset_texels[read_index_start..read_index_end]);
             }
+
+            let mut texture_info = self.tileset_texture.as_image_copy();
+            texture_info.origin.z = tile_id;
+
+            queue.write_texture(
+                texture_info,
+                &custom_data,
+                wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(256),
+                    rows_per_image: Some(16),
+                },
+                wgpu::Extent3d {
+                    width: 16,
+                    height: 16,
+                    depth_or_array_layers: 1,
+                },
+            );
         }
 
+        let tile_visibility: Vec<u8> = (0..=255)
+            .map(|id| TileKind::from_id(id).uses_tileset() as u8)
+            .collect();
+
+        queue.write_texture(
+            self.tile_visibility_texture.as_image_copy(),
+            &tile_visibility,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(256),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: 256,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+
         let mut tiledata = Vec::with_capacity(1024 * 1024);
         for y in 0..1024 {
             for x in 0..1024 {
@@ -313,6 +415,38 @@ impl MapRenderer {
         );
     }
 
+    // Sets the colors blended over each tile. The overlay is 1024x1024, one pixel per tile, and the alpha is how
+    // strongly the color covers the tile.
+    pub fn set_overlay(&mut self, overlay: &RgbaImage, queue: &wgpu::Queue) {
+        if overlay.dimensions() != (1024, 1024) {
+            log::warn!(
+                "overlay is {}x{} instead of 1024x1024",
+                overlay.width(),
+                overlay.height()
+            );
+            return;
+        }
+
+        queue.write_texture(
+            self.overlay_texture.as_image_copy(),
+            overlay.as_raw(),
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(1024 * 4),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: 1024,
+                height: 1024,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    pub fn clear_overlay(&mut self, queue: &wgpu::Queue) {
+        self.set_overlay(&RgbaImage::new(1024, 1024), queue);
+    }
+
     pub fn update(&mut self, camera: &Camera, queue: &wgpu::Queue) {
         self.uniform_state.mvp = camera.projection() * camera.view();
 
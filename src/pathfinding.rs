@@ -0,0 +1,323 @@
+use crate::{
+    map::{Map, TILE_ID_WORMHOLE},
+    walkability::{DEFAULT_SHIP_RADIUS, WalkabilityGrid},
+};
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+// Movement costs scaled so diagonal moves can stay integers.
+const STRAIGHT_COST: u32 = 10;
+const DIAGONAL_COST: u32 = 14;
+
+// Offsets to the tiles around a tile. Straight moves come first.
+pub const NEIGHBORS: [(i32, i32); 8] = [
+    (1, 0),
+    (-1, 0),
+    (0, 1),
+    (0, -1),
+    (1, 1),
+    (1, -1),
+    (-1, 1),
+    (-1, -1),
+];
+
+pub struct PathOptions {
+    // Radius of the ship in pixels.
+    pub radius: u16,
+    // Lets paths go through doors as if they were open.
+    pub doors_passable: bool,
+    // Tiles that a ship can come out at after flying into a wormhole. Wormholes are normal open space when this is
+    // empty, since the exits depend on the zone.
+    pub wormhole_exits: Vec<(u16, u16)>,
+    // Removes waypoints that can be skipped by flying in a straight line.
+    pub smooth: bool,
+}
+
+impl Default for PathOptions {
+    fn default() -> Self {
+        Self {
+            radius: DEFAULT_SHIP_RADIUS,
+            doors_passable: false,
+            wormhole_exits: vec![],
+            smooth: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Waypoint {
+    pub x: u16,
+    pub y: u16,
+    // The ship got here by going through a wormhole instead of flying from the previous waypoint.
+    pub teleported: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path {
+    pub waypoints: Vec<Waypoint>,
+}
+
+impl Path {
+    // Distance flown in tiles. Wormhole teleports don't add to it.
+    pub fn length(&self) -> f32 {
+        self.waypoints
+            .windows(2)
+            .filter(|pair| !pair[1].teleported)
+            .map(|pair| {
+                let dx = pair[1].x as f32 - pair[0].x as f32;
+                let dy = pair[1].y as f32 - pair[0].y as f32;
+                (dx * dx + dy * dy).sqrt()
+            })
+            .sum()
+    }
+
+    // Every tile the ship flies over, in order. Teleports are not filled in.
+    pub fn tiles(&self) -> Vec<(u16, u16)> {
+        let mut tiles = vec![];
+
+        for (i, waypoint) in self.waypoints.iter().enumerate() {
+            if i == 0 || waypoint.teleported {
+                tiles.push((waypoint.x, waypoint.y));
+                continue;
+            }
+
+            let previous = self.waypoints[i - 1];
+            let line = line_tiles((previous.x, previous.y), (waypoint.x, waypoint.y));
+            tiles.extend_from_slice(&line[1..]);
+        }
+
+        tiles
+    }
+}
+
+// Finds paths for one ship configuration. The walkability grid is built once so many paths can be found quickly.
+pub struct Pathfinder {
+    grid: WalkabilityGrid,
+    // Tiles covered by a wormhole, only filled in when there are wormhole exits.
+    wormholes: Vec<bool>,
+    wormhole_exits: Vec<(u16, u16)>,
+    smooth: bool,
+}
+
+impl Pathfinder {
+    pub fn new(map: &Map, options: &PathOptions) -> Self {
+        let grid = if options.doors_passable {
+            WalkabilityGrid::with_open_doors(map, options.radius)
+        } else {
+            WalkabilityGrid::new(map, options.radius)
+        };
+
+        let wormholes = if options.wormhole_exits.is_empty() {
+            vec![]
+        } else {
+            let occupancy = map.occupancy_grid();
+
+            (0..1024)
+                .flat_map(|y| (0..1024).map(move |x| (x, y)))
+                .map(|(x, y)| occupancy.get(x, y) == TILE_ID_WORMHOLE)
+                .collect()
+        };
+
+        // Exits the ship doesn't fit at can't be used.
+        let wormhole_exits = options
+            .wormhole_exits
+            .iter()
+            .copied()
+            .filter(|(x, y)| grid.is_walkable(*x, *y))
+            .collect();
+
+        Self {
+            grid,
+            wormholes,
+            wormhole_exits,
+            smooth: options.smooth,
+        }
+    }
+
+    pub fn grid(&self) -> &WalkabilityGrid {
+        &self.grid
+    }
+
+    // Finds the shortest path between the two tiles with A*. Ships move in 8 directions but can't cut corners, and
+    // both ends need to be tiles the ship fits on.
+    pub fn find_path(&self, start: (u16, u16), goal: (u16, u16)) -> Option<Path> {
+        if !self.grid.is_walkable(start.0, start.1) || !self.grid.is_walkable(goal.0, goal.1) {
+            return None;
+        }
+
+        let index = |x: u16, y: u16| y as usize * 1024 + x as usize;
+        let position = |index: usize| ((index % 1024) as u16, (index / 1024) as u16);
+
+        // Teleporting is free, so the best any teleport can do is the distance from the closest exit.
+        let best_exit_cost = self
+            .wormhole_exits
+            .iter()
+            .map(|exit| octile_distance(*exit, goal))
+            .min();
+        let heuristic = |tile: (u16, u16)| {
+            let direct = octile_distance(tile, goal);
+            best_exit_cost.map_or(direct, |exit_cost| direct.min(exit_cost))
+        };
+
+        let mut costs = vec![u32::MAX; 1024 * 1024];
+        // Index of the tile each tile was reached from. Teleports are marked so the path can record them.
+        let mut came_from = vec![usize::MAX; 1024 * 1024];
+        let mut teleported = vec![false; 1024 * 1024];
+        let mut open = BinaryHeap::new();
+
+        costs[index(start.0, start.1)] = 0;
+        open.push(Reverse((heuristic(start), index(start.0, start.1))));
+
+        while let Some(Reverse((estimate, current))) = open.pop() {
+            let (x, y) = position(current);
+
+            if (x, y) == goal {
+                return Some(self.build_path(&came_from, &teleported, current));
+            }
+
+            let cost = costs[current];
+
+            // The tile was already reached with a lower cost after this entry was added.
+            if estimate > cost + heuristic((x, y)) {
+                continue;
+            }
+            let mut visit = |next: usize, next_cost: u32, teleport: bool| {
+                if next_cost < costs[next] {
+                    costs[next] = next_cost;
+                    came_from[next] = current;
+                    teleported[next] = teleport;
+                    open.push(Reverse((next_cost + heuristic(position(next)), next)));
+                }
+            };
+
+            for (dx, dy) in NEIGHBORS {
+                let (next_x, next_y) = (x as i32 + dx, y as i32 + dy);
+
+                if !grid_walkable(&self.grid, next_x, next_y) {
+                    continue;
+                }
+
+                let step_cost = if dx != 0 && dy != 0 {
+                    // Moving diagonally past a wall would clip its corner.
+                    if !grid_walkable(&self.grid, next_x, y as i32)
+                        || !grid_walkable(&self.grid, x as i32, next_y)
+                    {
+                        continue;
+                    }
+
+                    DIAGONAL_COST
+                } else {
+                    STRAIGHT_COST
+                };
+
+                visit(index(next_x as u16, next_y as u16), cost + step_cost, false);
+            }
+
+            if !self.wormholes.is_empty() && self.wormholes[current] {
+                for exit in &self.wormhole_exits {
+                    visit(index(exit.0, exit.1), cost, true);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn build_path(&self, came_from: &[usize], teleported: &[bool], end: usize) -> Path {
+        let mut waypoints = vec![];
+        let mut current = end;
+
+        loop {
+            waypoints.push(Waypoint {
+                x: (current % 1024) as u16,
+                y: (current / 1024) as u16,
+                teleported: teleported[current],
+            });
+
+            if came_from[current] == usize::MAX {
+                break;
+            }
+
+            current = came_from[current];
+        }
+
+        waypoints.reverse();
+
+        if self.smooth {
+            waypoints = self.smooth_waypoints(&waypoints);
+        }
+
+        Path { waypoints }
+    }
+
+    // Keeps skipping ahead to the furthest waypoint that can be reached in a straight line.
+    fn smooth_waypoints(&self, waypoints: &[Waypoint]) -> Vec<Waypoint> {
+        let mut smoothed = vec![waypoints[0]];
+        let mut anchor = 0;
+
+        while anchor + 1 < waypoints.len() {
+            let mut next = anchor + 1;
+
+            while next + 1 < waypoints.len()
+                && !waypoints[next + 1].teleported
+                && self.has_line_of_sight(waypoints[anchor], waypoints[next + 1])
+            {
+                next += 1;
+            }
+
+            smoothed.push(waypoints[next]);
+            anchor = next;
+        }
+
+        smoothed
+    }
+
+    fn has_line_of_sight(&self, from: Waypoint, to: Waypoint) -> bool {
+        line_tiles((from.x, from.y), (to.x, to.y))
+            .iter()
+            .all(|(x, y)| self.grid.is_walkable(*x, *y))
+    }
+}
+
+// Every tile a line between the two tile centers passes through. Both tiles are added where the line goes exactly
+// through a corner, so a clear line never cuts a corner.
+pub fn line_tiles(from: (u16, u16), to: (u16, u16)) -> Vec<(u16, u16)> {
+    let (mut x, mut y) = (from.0 as i32, from.1 as i32);
+    let (dx, dy) = ((to.0 as i32 - x).abs(), (to.1 as i32 - y).abs());
+    let (step_x, step_y) = ((to.0 as i32 - x).signum(), (to.1 as i32 - y).signum());
+    let (mut moved_x, mut moved_y) = (0, 0);
+    let mut tiles = vec![from];
+
+    while moved_x < dx || moved_y < dy {
+        let decision = (1 + 2 * moved_x) * dy - (1 + 2 * moved_y) * dx;
+
+        if decision == 0 {
+            tiles.push(((x + step_x) as u16, y as u16));
+            tiles.push((x as u16, (y + step_y) as u16));
+            x += step_x;
+            y += step_y;
+            moved_x += 1;
+            moved_y += 1;
+        } else if decision < 0 {
+            x += step_x;
+            moved_x += 1;
+        } else {
+            y += step_y;
+            moved_y += 1;
+        }
+
+        tiles.push((x as u16, y as u16));
+    }
+
+    tiles
+}
+
+fn octile_distance(from: (u16, u16), to: (u16, u16)) -> u32 {
+    let dx = from.0.abs_diff(to.0) as u32;
+    let dy = from.1.abs_diff(to.1) as u32;
+
+    STRAIGHT_COST * dx.max(dy) + (DIAGONAL_COST - STRAIGHT_COST) * dx.min(dy)
+}
+
+fn grid_walkable(grid: &WalkabilityGrid, x: i32, y: i32) -> bool {
+    x >= 0 && y >= 0 && grid.is_walkable(x as u16, y as u16)
+}
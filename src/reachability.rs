@@ -0,0 +1,206 @@
+use crate::{elvl::TileRect, pathfinding::NEIGHBORS, walkability::WalkabilityGrid};
+use image::{Rgba, RgbaImage};
+use std::{cmp::Reverse, collections::VecDeque, fs};
+
+// Settings files have spawn points for up to this many teams.
+const SPAWN_TEAM_COUNT: usize = 4;
+
+// Open space that can't be reached from any spawn.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pocket {
+    // Number of tiles in the pocket.
+    pub area: u32,
+    pub bounds: TileRect,
+    // The first tile of the pocket in row order.
+    pub tile: (u16, u16),
+}
+
+pub struct Reachability {
+    reachable: Vec<bool>,
+    // Sorted from the largest area to the smallest.
+    pub pockets: Vec<Pocket>,
+    // Spawns the ship doesn't fit at, so nothing was reached from them.
+    pub blocked_spawns: Vec<(u16, u16)>,
+}
+
+impl Reachability {
+    // Flood fills the walkable tiles from the spawns. Ships move the same way as in pathfinding, so diagonal moves
+    // can't cut corners.
+    pub fn new(grid: &WalkabilityGrid, spawns: &[(u16, u16)]) -> Self {
+        let mut reachable = vec![false; 1024 * 1024];
+        let mut blocked_spawns = vec![];
+        let mut spawn_tiles = vec![];
+
+        for spawn in spawns {
+            if grid.is_walkable(spawn.0, spawn.1) {
+                spawn_tiles.push(*spawn);
+            } else {
+                blocked_spawns.push(*spawn);
+            }
+        }
+
+        flood_fill(grid, &spawn_tiles, &mut reachable, |_| {});
+
+        // Every open tile that wasn't reached is part of a pocket. Filling the pockets marks them as visited too.
+        let mut visited = reachable.clone();
+        let mut pockets = vec![];
+
+        for y in 0..1024 {
+            for x in 0..1024 {
+                if visited[y as usize * 1024 + x as usize] || !grid.is_walkable(x, y) {
+                    continue;
+                }
+
+                let mut pocket = Pocket {
+                    area: 0,
+                    bounds: TileRect {
+                        left: x,
+                        top: y,
+                        right: x + 1,
+                        bottom: y + 1,
+                    },
+                    tile: (x, y),
+                };
+
+                flood_fill(grid, &[(x, y)], &mut visited, |(tile_x, tile_y)| {
+                    pocket.area += 1;
+                    pocket.bounds.left = pocket.bounds.left.min(tile_x);
+                    pocket.bounds.top = pocket.bounds.top.min(tile_y);
+                    pocket.bounds.right = pocket.bounds.right.max(tile_x + 1);
+                    pocket.bounds.bottom = pocket.bounds.bottom.max(tile_y + 1);
+                });
+
+                pockets.push(pocket);
+            }
+        }
+
+        pockets.sort_by_key(|pocket| Reverse(pocket.area));
+
+        Self {
+            reachable,
+            pockets,
+            blocked_spawns,
+        }
+    }
+
+    pub fn is_reachable(&self, x: u16, y: u16) -> bool {
+        if x >= 1024 || y >= 1024 {
+            return false;
+        }
+
+        self.reachable[y as usize * 1024 + x as usize]
+    }
+
+    // Total number of open tiles that can't be reached.
+    pub fn unreachable_area(&self) -> u32 {
+        self.pockets.iter().map(|pocket| pocket.area).sum()
+    }
+
+    // Overlay for MapRenderer that highlights the unreachable open space.
+    pub fn overlay(&self, grid: &WalkabilityGrid) -> RgbaImage {
+        RgbaImage::from_fn(1024, 1024, |x, y| {
+            let (x, y) = (x as u16, y as u16);
+
+            if grid.is_walkable(x, y) && !self.is_reachable(x, y) {
+                Rgba([255, 0, 0, 128])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        })
+    }
+}
+
+// Reads the spawn points from the [Spawn] section of an arena settings file. Teams without a spawn point set are
+// skipped, since those spawn randomly.
+pub fn read_spawns(settings: &str) -> Vec<(u16, u16)> {
+    let mut positions = [(0u16, 0u16); SPAWN_TEAM_COUNT];
+    let mut in_spawn_section = false;
+
+    for line in settings.lines() {
+        let line = line.split(';').next().unwrap_or_default().trim();
+
+        if let Some(section) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            in_spawn_section = section.trim().eq_ignore_ascii_case("Spawn");
+            continue;
+        }
+
+        if !in_spawn_section {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let Ok(value) = value.trim().parse::<u16>() else {
+            continue;
+        };
+
+        let key = key.trim().to_ascii_lowercase();
+
+        for (team, position) in positions.iter_mut().enumerate() {
+            if key == format!("team{}-x", team) {
+                position.0 = value;
+            } else if key == format!("team{}-y", team) {
+                position.1 = value;
+            }
+        }
+    }
+
+    positions
+        .into_iter()
+        .filter(|(x, y)| *x != 0 && *y != 0 && *x < 1024 && *y < 1024)
+        .collect()
+}
+
+pub fn load_spawns(filename: &str) -> anyhow::Result<Vec<(u16, u16)>> {
+    let data = fs::read(filename)?;
+
+    Ok(read_spawns(&String::from_utf8_lossy(&data)))
+}
+
+// Marks every walkable tile reachable from the starts and calls visit with each newly marked tile.
+fn flood_fill(
+    grid: &WalkabilityGrid,
+    starts: &[(u16, u16)],
+    marked: &mut [bool],
+    mut visit: impl FnMut((u16, u16)),
+) {
+    let walkable = |x: i32, y: i32| x >= 0 && y >= 0 && grid.is_walkable(x as u16, y as u16);
+    let mut queue = VecDeque::new();
+
+    for start in starts {
+        let index = start.1 as usize * 1024 + start.0 as usize;
+
+        if !marked[index] {
+            marked[index] = true;
+            visit(*start);
+            queue.push_back(*start);
+        }
+    }
+
+    while let Some((x, y)) = queue.pop_front() {
+        for (dx, dy) in NEIGHBORS {
+            let (next_x, next_y) = (x as i32 + dx, y as i32 + dy);
+
+            if !walkable(next_x, next_y) {
+                continue;
+            }
+
+            // Moving diagonally past a wall would clip its corner.
+            if dx != 0 && dy != 0 && (!walkable(next_x, y as i32) || !walkable(x as i32, next_y)) {
+                continue;
+            }
+
+            let index = next_y as usize * 1024 + next_x as usize;
+
+            if !marked[index] {
+                marked[index] = true;
+                visit((next_x as u16, next_y as u16));
+                queue.push_back((next_x as u16, next_y as u16));
+            }
+        }
+    }
+}
@@ -33,12 +33,23 @@ var s_diffuse: sampler;
 @binding(3)
 var t_tiledata: texture_2d<u32>;
 
+// Non-zero for tile ids that are drawn from the tileset.
+@group(0)
+@binding(4)
+var t_tile_visibility: texture_2d<u32>;
+
+// Drawn over the map to highlight tiles, such as paths. The alpha is how much it covers the tile.
+@group(0)
+@binding(5)
+var t_overlay: texture_2d<f32>;
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
   let x: f32 = in.world_position.x;
   let y: f32 = in.world_position.y;
 
   var tile_id: u32 = 0;
+  var overlay: vec4<f32> = vec4<f32>(0.0, 0.0, 0.0, 0.0);
 
   if x < 0.0 || y < 0.0 || x > 1024.0 || y > 1024.0 {
     tile_id = 20;
@@ -47,14 +58,26 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let tile_y = u32(in.world_position.y);
 
     tile_id = textureLoad(t_tiledata, vec2<u32>(tile_x, tile_y), 0).r;
+    overlay = textureLoad(t_overlay, vec2<u32>(tile_x, tile_y), 0);
   }
 
-  if tile_id == 0 || tile_id == 170 || tile_id == 172 || tile_id > 190 || (tile_id >= 162 && tile_id <= 169) {
+  let uv: vec2<f32> = modf(in.world_position + vec2<f32>(2.0, 2.0)).fract;
+  // Tiles that aren't drawn still sample a valid layer, the sample is just not used.
+  let layer: u32 = min(max(tile_id, 1u) - 1u, 189u);
+  let sample: vec4<f32> = textureSample(t_diffuse, s_diffuse, uv, layer);
+
+  // Transparent tileset colors are keyed out when the tileset is loaded.
+  let drawn = textureLoad(t_tile_visibility, vec2<u32>(tile_id, 0), 0).r != 0 && sample.a >= 0.5;
+
+  if !drawn && overlay.a == 0.0 {
     discard;
   }
 
-  let uv: vec2<f32> = modf(in.world_position + vec2<f32>(2.0, 2.0)).fract;
-  let sample: vec4<f32> = textureSample(t_diffuse, s_diffuse, uv, tile_id - 1);
-  //let sample = vec4<f32>(uv, 0.0, 0.0);
-  return sample;
+  var color: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+
+  if drawn {
+    color = sample.rgb;
+  }
+
+  return vec4<f32>(mix(color, overlay.rgb, overlay.a), 1.0);
 }
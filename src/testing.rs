@@ -0,0 +1,111 @@
+// Helpers shared by the unit tests.
+use crate::{
+    elvl,
+    map::{Map, TileId},
+};
+
+// Small xorshift generator so the property tests are repeatable without extra dependencies.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    pub fn bytes(&mut self, max_len: u64) -> Vec<u8> {
+        (0..self.below(max_len + 1))
+            .map(|_| self.next() as u8)
+            .collect()
+    }
+
+    // Overwrites a few random bytes and cuts off a random amount of the end.
+    pub fn mutate(&mut self, data: &mut Vec<u8>) {
+        for _ in 0..self.below(8) + 1 {
+            let index = self.below(data.len() as u64) as usize;
+            data[index] = self.next() as u8;
+        }
+
+        data.truncate(self.below(data.len() as u64 + 1) as usize);
+    }
+}
+
+pub const BITMAP_FILE_HEADER_SIZE: usize = 14;
+
+pub fn chunk(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    elvl::write_chunk(&mut data, u32::from_le_bytes(*kind), payload);
+
+    data
+}
+
+// Wraps chunks in an eLVL metadata header.
+pub fn metadata(chunks: &[u8]) -> Vec<u8> {
+    let mut data = b"elvl".to_vec();
+    data.extend_from_slice(&(12 + chunks.len() as u32).to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(chunks);
+
+    data
+}
+
+// Builds a map file with only a bitmap file header in front of the metadata, the same as maps without a tileset.
+pub fn map_file(metadata: &[u8], tiledata: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; BITMAP_FILE_HEADER_SIZE];
+    let tiledata_offset = BITMAP_FILE_HEADER_SIZE + metadata.len();
+
+    data[0..2].copy_from_slice(b"BM");
+    data[2..6].copy_from_slice(&(tiledata_offset as u32).to_le_bytes());
+    data[6..10].copy_from_slice(&(BITMAP_FILE_HEADER_SIZE as u32).to_le_bytes());
+    data.extend_from_slice(metadata);
+    data.extend_from_slice(tiledata);
+
+    data
+}
+
+pub fn tile(x: u16, y: u16, id: u8) -> [u8; 4] {
+    ((id as u32) << 24 | (y as u32) << 12 | x as u32).to_le_bytes()
+}
+
+pub fn add_random_spans(rng: &mut Rng, region: &mut elvl::Region) {
+    for _ in 0..rng.below(20) {
+        let start = rng.below(1024) as u16;
+        let end = start + rng.below(1024 - start as u64 + 1) as u16;
+
+        region.set_span(rng.below(1024) as u16, start, end);
+    }
+}
+
+// Builds a map with random tiles and regions. Some of the regions share a name.
+pub fn random_map(rng: &mut Rng) -> Map {
+    let mut map = Map::empty();
+
+    // Ids the client ignores are left out since they show up as diagnostics.
+    for _ in 0..rng.below(2000) {
+        let index = rng.below(1024 * 1024) as usize;
+        map.tiles[index] = rng.below(190) as TileId + 1;
+    }
+
+    map.attributes_mut().set("NAME", "test");
+
+    for _ in 0..rng.below(5) {
+        let mut region = elvl::Region::empty();
+        region.name = format!("region {}", rng.below(3)).into_bytes();
+        add_random_spans(rng, &mut region);
+
+        map.elvl.push(elvl::Chunk::Region(region));
+    }
+
+    map.rebuild_region_index();
+    map
+}
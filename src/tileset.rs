@@ -0,0 +1,60 @@
+use anyhow::*;
+use image::{Rgba, RgbaImage};
+use std::{io::Cursor, sync::OnceLock};
+
+pub const TILESET_WIDTH: u32 = 304;
+pub const TILESET_HEIGHT: u32 = 160;
+
+// Tileset the viewer shows for maps without one, stored as an 8-bit bitmap in the same layout as map tilesets.
+// This is a plain placeholder and not the client's default tileset, so it's never written into maps. The client
+// uses its own default tileset for maps without one.
+pub const PLACEHOLDER_TILESET_BITMAP: &[u8] = include_bytes!("../assets/placeholder_tileset.bmp");
+
+pub fn placeholder_tileset() -> &'static RgbaImage {
+    static PLACEHOLDER_TILESET: OnceLock<RgbaImage> = OnceLock::new();
+
+    PLACEHOLDER_TILESET.get_or_init(|| {
+        decode_tileset(PLACEHOLDER_TILESET_BITMAP)
+            .expect("placeholder tileset should be a valid bitmap")
+    })
+}
+
+// Decodes a tileset bitmap. Any bit depth the bitmap decoder supports works, which covers the 8-bit paletted and
+// 24-bit bitmaps that map editors write.
+pub fn decode_tileset(data: &[u8]) -> anyhow::Result<RgbaImage> {
+    let img = image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .decode()
+        .context("failed to decode tileset bitmap")?;
+
+    normalize_tileset(img.into_rgba8())
+}
+
+// Checks the tileset dimensions and makes the colors Continuum treats as transparent fully transparent.
+// The color values are kept so the tileset can be written back out as the same bitmap.
+pub fn normalize_tileset(mut tileset: RgbaImage) -> anyhow::Result<RgbaImage> {
+    if tileset.dimensions() != (TILESET_WIDTH, TILESET_HEIGHT) {
+        bail!(
+            "tileset must be {}x{} pixels but it is {}x{}",
+            TILESET_WIDTH,
+            TILESET_HEIGHT,
+            tileset.width(),
+            tileset.height()
+        );
+    }
+
+    for pixel in tileset.pixels_mut() {
+        let Rgba([r, g, b, _]) = *pixel;
+
+        if is_transparent_color(r, g, b) {
+            pixel.0[3] = 0;
+        }
+    }
+
+    Ok(tileset)
+}
+
+// Black and magenta are both used as the transparent color in tilesets.
+pub fn is_transparent_color(r: u8, g: u8, b: u8) -> bool {
+    matches!((r, g, b), (0, 0, 0) | (255, 0, 255))
+}
@@ -0,0 +1,232 @@
+use crate::{
+    collision::TileKind,
+    map::{MAX_OBJECT_SIZE, Map, TileId},
+};
+use image::{GrayImage, Luma};
+
+// Continuum uses this radius for ships that don't have one set.
+pub const DEFAULT_SHIP_RADIUS: u16 = 14;
+
+const TILE_SIZE: u16 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ship {
+    Warbird,
+    Javelin,
+    Spider,
+    Leviathan,
+    Terrier,
+    Weasel,
+    Lancaster,
+    Shark,
+}
+
+impl Ship {
+    pub const ALL: [Ship; 8] = [
+        Ship::Warbird,
+        Ship::Javelin,
+        Ship::Spider,
+        Ship::Leviathan,
+        Ship::Terrier,
+        Ship::Weasel,
+        Ship::Lancaster,
+        Ship::Shark,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Ship::Warbird => "Warbird",
+            Ship::Javelin => "Javelin",
+            Ship::Spider => "Spider",
+            Ship::Leviathan => "Leviathan",
+            Ship::Terrier => "Terrier",
+            Ship::Weasel => "Weasel",
+            Ship::Lancaster => "Lancaster",
+            Ship::Shark => "Shark",
+        }
+    }
+}
+
+// Radius in pixels of each ship, from the arena's <Ship>:Radius settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShipRadii {
+    radii: [u16; 8],
+}
+
+impl Default for ShipRadii {
+    fn default() -> Self {
+        Self {
+            radii: [DEFAULT_SHIP_RADIUS; 8],
+        }
+    }
+}
+
+impl ShipRadii {
+    pub fn radius(&self, ship: Ship) -> u16 {
+        self.radii[ship as usize]
+    }
+
+    // A radius of 0 uses the default radius like the client does.
+    pub fn set_radius(&mut self, ship: Ship, radius: u16) {
+        self.radii[ship as usize] = if radius == 0 {
+            DEFAULT_SHIP_RADIUS
+        } else {
+            radius
+        };
+    }
+}
+
+// Which tiles a ship of some radius can be centered on without touching anything solid. Ships are treated as a square
+// that extends radius pixels from the center of the tile, the same shape the client collides with.
+pub struct WalkabilityGrid {
+    radius: u16,
+    // How many tiles the ship reaches past the tile it's centered on.
+    reach: u16,
+    // Doors are treated as open instead of solid.
+    doors_open: bool,
+    solid: Vec<bool>,
+    walkable: Vec<bool>,
+}
+
+impl WalkabilityGrid {
+    pub fn new(map: &Map, radius: u16) -> Self {
+        Self::build(map, radius, false)
+    }
+
+    // Same as new, but ships can pass through doors as if they were always open.
+    pub fn with_open_doors(map: &Map, radius: u16) -> Self {
+        Self::build(map, radius, true)
+    }
+
+    fn build(map: &Map, radius: u16, doors_open: bool) -> Self {
+        let occupancy = map.occupancy_grid();
+        let mut solid = vec![false; 1024 * 1024];
+
+        for y in 0..1024 {
+            for x in 0..1024 {
+                solid[y as usize * 1024 + x as usize] =
+                    is_blocking(occupancy.get(x, y), doors_open);
+            }
+        }
+
+        // The ship is centered on the tile, so half a tile of the radius is inside of it.
+        let reach = (radius + TILE_SIZE / 2 - 1) / TILE_SIZE;
+
+        let mut grid = Self {
+            radius,
+            reach,
+            doors_open,
+            solid,
+            walkable: vec![false; 1024 * 1024],
+        };
+
+        grid.build_walkable();
+        grid
+    }
+
+    pub fn radius(&self) -> u16 {
+        self.radius
+    }
+
+    pub fn is_walkable(&self, x: u16, y: u16) -> bool {
+        if x >= 1024 || y >= 1024 {
+            return false;
+        }
+
+        self.walkable[y as usize * 1024 + x as usize]
+    }
+
+    // Updates the grid after the tile at x, y changed in the map. Only the tiles that the change can reach are
+    // recomputed.
+    pub fn update(&mut self, map: &Map, x: u16, y: u16) {
+        if x >= 1024 || y >= 1024 {
+            return;
+        }
+
+        // An object placed or removed here changes what's solid across its whole footprint.
+        let right = (x + MAX_OBJECT_SIZE).min(1024);
+        let bottom = (y + MAX_OBJECT_SIZE).min(1024);
+
+        for solid_y in y..bottom {
+            for solid_x in x..right {
+                self.solid[solid_y as usize * 1024 + solid_x as usize] =
+                    is_blocking(map.occupant(solid_x, solid_y), self.doors_open);
+            }
+        }
+
+        for walk_y in y.saturating_sub(self.reach)..(bottom + self.reach).min(1024) {
+            for walk_x in x.saturating_sub(self.reach)..(right + self.reach).min(1024) {
+                self.walkable[walk_y as usize * 1024 + walk_x as usize] =
+                    self.fits_at(walk_x, walk_y);
+            }
+        }
+    }
+
+    // Walkable tiles are white and blocked tiles are black.
+    pub fn to_image(&self) -> GrayImage {
+        GrayImage::from_fn(1024, 1024, |x, y| {
+            Luma([if self.is_walkable(x as u16, y as u16) {
+                255
+            } else {
+                0
+            }])
+        })
+    }
+
+    pub fn save_bitmap(&self, filename: &str) -> anyhow::Result<()> {
+        self.to_image()
+            .save_with_format(filename, image::ImageFormat::Bmp)?;
+
+        Ok(())
+    }
+
+    fn fits_at(&self, x: u16, y: u16) -> bool {
+        // Everything outside of the map is solid.
+        if x < self.reach || y < self.reach || x + self.reach >= 1024 || y + self.reach >= 1024 {
+            return false;
+        }
+
+        (y - self.reach..=y + self.reach).all(|check_y| {
+            (x - self.reach..=x + self.reach)
+                .all(|check_x| !self.solid[check_y as usize * 1024 + check_x as usize])
+        })
+    }
+
+    fn build_walkable(&mut self) {
+        // Summed area table of solid tiles so each tile only needs a constant number of lookups.
+        let mut sums = vec![0u32; 1025 * 1025];
+
+        for y in 0..1024 {
+            let mut row_sum = 0;
+
+            for x in 0..1024 {
+                row_sum += self.solid[y * 1024 + x] as u32;
+                sums[(y + 1) * 1025 + x + 1] = sums[y * 1025 + x + 1] + row_sum;
+            }
+        }
+
+        let reach = self.reach as usize;
+
+        for y in 0..1024 {
+            for x in 0..1024 {
+                if x < reach || y < reach || x + reach >= 1024 || y + reach >= 1024 {
+                    continue;
+                }
+
+                let (left, top) = (x - reach, y - reach);
+                let (right, bottom) = (x + reach + 1, y + reach + 1);
+                let solid_count = sums[bottom * 1025 + right] + sums[top * 1025 + left]
+                    - sums[top * 1025 + right]
+                    - sums[bottom * 1025 + left];
+
+                self.walkable[y * 1024 + x] = solid_count == 0;
+            }
+        }
+    }
+}
+
+fn is_blocking(id: TileId, doors_open: bool) -> bool {
+    let kind = TileKind::from_id(id);
+
+    kind.is_solid() && !(doors_open && kind == TileKind::Door)
+}